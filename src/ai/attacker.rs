@@ -0,0 +1,249 @@
+use super::{act, facing, rotation, Bot, Difficulty};
+use crate::{Action, Direction, Game, Point, Tile, Turn};
+use std::collections::{HashMap, HashSet};
+
+/// Number of moves ahead on its route an attacker looks towards
+const LOOKAHEAD: usize = 6;
+
+/// Turns an attacker waits for a way past the guards before risking it
+const PATIENCE: usize = 3;
+
+/// A computer attacker, which makes for its target by the way least likely
+/// to be seen by the guards it knows about
+pub struct Attacker {
+    difficulty: Difficulty,
+    // Where each guard was last seen, and how many turns ago
+    guards: Vec<Option<((Point, Direction), usize)>>,
+    // Turns spent waiting for a way past the guards
+    waited: usize,
+    // Tiles seen so far, as they were last seen
+    tiles: HashMap<Point, Tile>,
+}
+
+impl Attacker {
+    pub fn new(difficulty: Difficulty) -> Self {
+        Attacker {
+            difficulty,
+            guards: vec![],
+            waited: 0,
+            tiles: HashMap::new(),
+        }
+    }
+
+    /// Turns a guard is remembered for after going out of sight
+    fn memory(&self) -> usize {
+        match self.difficulty {
+            Difficulty::Easy => 0,
+            Difficulty::Normal => 2,
+            Difficulty::Hard => 5,
+        }
+    }
+
+    /// Extra cost of stepping where a guard in sight can see, and where a
+    /// guard that was seen before might still be looking
+    fn caution(&self) -> (usize, usize) {
+        match self.difficulty {
+            Difficulty::Easy => (2, 0),
+            Difficulty::Normal | Difficulty::Hard => (50, 10),
+        }
+    }
+
+    /// Update the map and where the guards are thought to be from what's in
+    /// sight
+    fn remember(&mut self, game: &Game) {
+        let cone = game.view_cone(game.positions[game.player]);
+        let sight: HashSet<Point> = cone.iter().map(|&(pos, _)| pos).collect();
+        self.tiles.extend(
+            game.map
+                .tiles()
+                .filter(|&(_, tile)| tile != Tile::Unknown)
+                .chain(cone),
+        );
+
+        let memory = self.memory();
+        self.guards.resize(game.guards.len(), None);
+        for (known, guard) in self.guards.iter_mut().zip(game.guards.iter()) {
+            *known = match (*guard, *known) {
+                (Some(guard), _) => Some((guard, 0)),
+                // Nobody's there any more
+                (None, Some(((pos, _), _))) if sight.contains(&pos) => None,
+                (None, Some((guard, age))) if age < memory => Some((guard, age + 1)),
+                _ => None,
+            };
+        }
+    }
+
+    /// Tiles guards in sight would spot it on, and those guards seen before
+    /// might
+    fn danger(&self, game: &Game) -> (HashSet<Point>, HashSet<Point>) {
+        let mut seen = HashSet::new();
+        let mut remembered = HashSet::new();
+        for &(guard, age) in self.guards.iter().flatten() {
+            let tiles = game.spots(Some(guard));
+            if age == 0 {
+                seen.extend(tiles);
+            } else {
+                remembered.extend(tiles);
+            }
+        }
+        (seen, remembered)
+    }
+
+    /// Whether to go on into sight of a guard, when the rest of the route
+    /// starts from the given point
+    fn dare(
+        &self,
+        game: &Game,
+        turn: &Turn,
+        from: Point,
+        path: &[Direction],
+        seen: &HashSet<Point>,
+    ) -> bool {
+        match self.difficulty {
+            Difficulty::Easy => return true,
+            // Sooner or later it has to go for it
+            _ if self.waited >= PATIENCE => return true,
+            Difficulty::Normal => return false,
+            Difficulty::Hard => (),
+        }
+
+        // Only if it never strays too far from the edge of their sight to
+        // be caught, and there's time to get back out of it this turn
+        let target = game.targets[game.player];
+        let exposed: Vec<Point> = game
+            .map
+            .follow(from, path)
+            .into_iter()
+            .take_while(|&pos| seen.contains(&pos) && Some(pos) != target)
+            .collect();
+        let escape = game.map.escape(seen);
+        let caught = game.config.detection_actions.max(1) as usize;
+        (exposed.len() as isize) < turn.actions
+            && exposed
+                .iter()
+                .all(|&pos| escape.get(pos).is_some_and(|moves| moves < caught))
+    }
+
+    /// Route to a guard in sight that can be crept up on and taken out
+    /// this turn without being seen, for those bold enough
+    fn ambush(
+        &self,
+        game: &Game,
+        turn: &Turn,
+        from: Point,
+        seen: &HashSet<Point>,
+    ) -> Option<Vec<Direction>> {
+        if self.difficulty != Difficulty::Hard {
+            return None;
+        }
+        game.guards
+            .iter()
+            .flatten()
+            .filter(|&&(guard, _)| guard != from)
+            .filter_map(|&(guard, _)| {
+                let path = game.map.cheapest_path(from, guard, |pos| {
+                    (pos == guard || !seen.contains(&pos)).then_some(1)
+                })?;
+                (path.len() as isize <= turn.actions).then_some(path)
+            })
+            .min_by_key(|path| path.len())
+    }
+}
+
+/// Number of rotations to turn from one direction to another
+fn turns(game: &Game, from: Direction, to: Direction) -> isize {
+    let eighths = from.eighths(to).unsigned_abs() as isize;
+    if game.config.diagonal {
+        eighths
+    } else {
+        (eighths + 1) / 2
+    }
+}
+
+impl Bot for Attacker {
+    fn play(&mut self, game: &Game) -> Vec<Action> {
+        let player = game.player;
+        let Some(target) = game.targets[player] else {
+            return vec![];
+        };
+        self.remember(game);
+
+        // Plan over what it's seen, taking anywhere else to be passable
+        let mut game = game.clone();
+        game.map.buf.fill(Tile::Unknown);
+        for (&(x, y), &tile) in self.tiles.iter() {
+            game.map.set(x as usize, y as usize, tile);
+        }
+
+        let (seen, remembered) = self.danger(&game);
+        let (spotted, suspected) = self.caution();
+        let cost = |pos: Point| {
+            Some(if seen.contains(&pos) {
+                1 + spotted
+            } else if remembered.contains(&pos) {
+                1 + suspected
+            } else {
+                1
+            })
+        };
+
+        let mut turn = game.begin(player);
+        let mut actions = vec![];
+        let mut moved = false;
+        while !turn.done && turn.actions > 0 {
+            let Some((pos, dir)) = game.positions[player] else {
+                break;
+            };
+            let path = self.ambush(&game, &turn, pos, &seen);
+            let Some(path) = path.or_else(|| game.map.cheapest_path(pos, target, cost)) else {
+                break;
+            };
+            let Some(&step) = path.first() else {
+                break;
+            };
+
+            let ahead = &path[..path.len().min(LOOKAHEAD)];
+            let look = game
+                .map
+                .follow(pos, ahead)
+                .last()
+                .map_or(step, |&ahead| facing(&game, pos, ahead));
+            let next = game.map.through(pos, step);
+            let exposed = next.is_some_and(|next| seen.contains(&next)) && !seen.contains(&pos);
+            let blocked = exposed && !self.dare(&game, &turn, pos, &path, &seen);
+
+            // Face the way ahead by the end of the turn, or while waiting,
+            // since that's where guards are seen from next turn
+            let last = turn.actions <= turns(&game, dir, look);
+            let wary = self.difficulty == Difficulty::Hard && !seen.contains(&pos);
+            if wary && (blocked || last) {
+                if let Some(rotate) = rotation(dir, look) {
+                    if !act(&mut game, &mut turn, &mut actions, rotate) {
+                        break;
+                    }
+                    continue;
+                }
+            }
+            if blocked {
+                break;
+            }
+            // Open any door in the way first
+            let action = if game.map.step(pos, step).is_some() {
+                Action::Move(step)
+            } else {
+                Action::Toggle(step)
+            };
+            if !act(&mut game, &mut turn, &mut actions, action) {
+                break;
+            }
+            moved = true;
+        }
+
+        if moved {
+            self.waited = 0;
+        } else {
+            self.waited += 1;
+        }
+        actions
+    }
+}
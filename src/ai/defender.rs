@@ -0,0 +1,329 @@
+use super::{act, facing, rotation, Bot, Routes};
+use crate::{Action, Direction, Game, Point, Tile, Turn};
+
+/// Closest a guard gets to an attacker they can see, out of their reach
+const KEEP_AWAY: usize = 3;
+
+/// Number of posts on each guard's patrol
+const POSTS: usize = 3;
+
+/// What a guard is doing this turn
+#[derive(Clone, Copy)]
+enum Goal {
+    // Keep watch on an attacker in sight
+    Watch(Point),
+    // Search where an attacker was last seen, looking the way they faced
+    Chase(usize, Point, Direction),
+    // Turn to look the way a noise came from
+    Listen(Direction),
+    // Stand at a post on patrol, looking out for anyone passing
+    Patrol(Point, Direction),
+}
+
+/// A computer defender, which patrols the chokepoints of the map, keeps
+/// watch on any attacker it spots, and chases them to where they were last
+/// seen once they slip away
+#[derive(Default)]
+pub struct Defender {
+    routes: Routes,
+    // Posts on each guard's patrol, and which one they're heading for
+    patrols: Vec<(Vec<(Point, Direction)>, usize)>,
+    // Where each attacker was last seen
+    sightings: Vec<Option<(Point, Direction)>>,
+}
+
+impl Defender {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Share out posts at chokepoints across the map between the guards,
+    /// each patrolling those nearest to them
+    fn plan(&mut self, game: &Game) {
+        let guards: Vec<Option<Point>> = game.guards.iter().map(|g| g.map(|g| g.0)).collect();
+        let mut candidates = chokepoints(game);
+        if candidates.len() < guards.len() * POSTS {
+            // Open caves have few chokepoints, so watch from anywhere
+            candidates = game
+                .map
+                .tiles()
+                .filter(|&(_, tile)| tile == Tile::Floor)
+                .map(|(pos, _)| (pos, outlook(game, pos)))
+                .collect();
+        }
+
+        // Spread posts out as far from each other as possible
+        let mut posts: Vec<(Point, Direction)> = vec![];
+        let mut nearest = vec![usize::MAX; candidates.len()];
+        while posts.len() < guards.len() * POSTS {
+            let best = (0..candidates.len())
+                .filter(|&i| nearest[i] > 0)
+                .max_by_key(|&i| nearest[i]);
+            let Some(best) = best else {
+                break;
+            };
+            let post = candidates[best];
+            posts.push(post);
+            for (i, (pos, _)) in candidates.iter().enumerate() {
+                let d = self.routes.distance(game, *pos, post.0).unwrap_or(0);
+                nearest[i] = nearest[i].min(d);
+            }
+        }
+
+        self.patrols = guards
+            .iter()
+            .map(|guard| {
+                let mut route = vec![];
+                if let Some(start) = guard {
+                    for _ in 0..POSTS {
+                        let closest = (0..posts.len()).min_by_key(|&i| {
+                            self.routes
+                                .distance(game, *start, posts[i].0)
+                                .unwrap_or(usize::MAX)
+                        });
+                        if let Some(i) = closest {
+                            route.push(posts.swap_remove(i));
+                        }
+                    }
+                }
+                (route, 0)
+            })
+            .collect();
+    }
+
+    /// What each guard should do this turn
+    fn goals(&mut self, game: &Game) -> Vec<Option<Goal>> {
+        let mut goals: Vec<Option<Goal>> = vec![None; game.guards.len()];
+
+        // Whoever's nearest goes after each attacker seen
+        for (attacker, sighting) in self.sightings.iter().enumerate() {
+            let Some((pos, dir)) = *sighting else {
+                continue;
+            };
+            let nearest = game
+                .guards
+                .iter()
+                .enumerate()
+                .filter(|&(g, guard)| guard.is_some() && goals[g].is_none())
+                .filter_map(|(g, guard)| {
+                    let from = guard.map(|g| g.0)?;
+                    Some((g, self.routes.distance(game, from, pos)?))
+                })
+                .min_by_key(|&(_, d)| d);
+            if let Some((g, _)) = nearest {
+                goals[g] = Some(if game.positions[attacker].is_some() {
+                    Goal::Watch(pos)
+                } else {
+                    Goal::Chase(attacker, pos, dir)
+                });
+            }
+        }
+
+        // Anyone who heard something looks round for it
+        for &(g, dir) in game.heard.iter() {
+            if game.guards[g].is_some() && goals[g].is_none() {
+                goals[g] = Some(Goal::Listen(dir));
+            }
+        }
+
+        // Everyone else carries on their patrol
+        for (g, goal) in goals.iter_mut().enumerate() {
+            if goal.is_none() && game.guards[g].is_some() {
+                if let Some((route, next)) = self.patrols.get(g) {
+                    if let Some(&(pos, dir)) = route.get(*next) {
+                        *goal = Some(Goal::Patrol(pos, dir));
+                    }
+                }
+            }
+        }
+        goals
+    }
+
+    /// Take a guard's next action towards their goal, returning whether
+    /// they've more to do
+    fn advance(
+        &mut self,
+        game: &mut Game,
+        turn: &mut Turn,
+        actions: &mut Vec<Action>,
+        guard: usize,
+        goal: Goal,
+    ) -> bool {
+        // Cycling between guards is free
+        while turn.guard != guard {
+            if !act(game, turn, actions, Action::Cycle) {
+                return false;
+            }
+        }
+        let Some((pos, dir)) = game.guards[guard] else {
+            return false;
+        };
+
+        let (dest, look) = match goal {
+            Goal::Watch(target) => {
+                let near = self
+                    .routes
+                    .distance(game, pos, target)
+                    .is_some_and(|d| d <= KEEP_AWAY);
+                if near {
+                    (pos, facing(game, pos, target))
+                } else {
+                    (target, facing(game, pos, target))
+                }
+            }
+            Goal::Chase(attacker, target, look) => {
+                if pos == target {
+                    self.sightings[attacker] = None;
+                }
+                (target, look)
+            }
+            Goal::Listen(look) => (pos, look),
+            Goal::Patrol(post, look) => (post, look),
+        };
+
+        if pos != dest {
+            let Some(step) = self.routes.step(game, pos, dest) else {
+                return false;
+            };
+            // Look where they're going when after someone
+            if !matches!(goal, Goal::Patrol(..)) {
+                if let Some(rotate) = rotation(dir, step) {
+                    return act(game, turn, actions, rotate);
+                }
+            }
+            // Open any door in the way first
+            if game.map.step(pos, step).is_none() {
+                return act(game, turn, actions, Action::Toggle(step));
+            }
+            return act(game, turn, actions, Action::Move(step));
+        }
+
+        if let Some(rotate) = rotation(dir, look) {
+            return act(game, turn, actions, rotate);
+        }
+
+        // Move on to the next post next turn
+        if let Goal::Patrol(..) = goal {
+            if let Some((route, next)) = self.patrols.get_mut(guard) {
+                *next = (*next + 1) % route.len();
+            }
+        }
+        false
+    }
+}
+
+impl Bot for Defender {
+    fn place(&mut self, game: &Game) -> Vec<Option<(Point, Direction)>> {
+        self.plan(game);
+        let guards: Vec<Option<(Point, Direction)>> = game
+            .guards
+            .iter()
+            .zip(self.patrols.iter())
+            .map(|(&guard, (route, _))| guard.and(route.first().copied()).or(guard))
+            .collect();
+
+        // Stay put rather than have two guards in one place
+        let mut taken: Vec<Point> = guards.iter().flatten().map(|g| g.0).collect();
+        taken.sort();
+        taken.dedup();
+        if taken.len() == guards.iter().flatten().count() {
+            guards
+        } else {
+            game.guards.clone()
+        }
+    }
+
+    fn play(&mut self, game: &Game) -> Vec<Action> {
+        if self.patrols.len() != game.guards.len() {
+            self.plan(game);
+        }
+
+        // Remember where everyone in sight was seen, forgetting anyone out
+        // of play
+        self.sightings.resize(game.positions.len(), None);
+        for (i, sighting) in self.sightings.iter_mut().enumerate() {
+            if game.positions[i].is_some() {
+                *sighting = game.positions[i];
+            } else if game.outcomes[i].is_some() {
+                *sighting = None;
+            }
+        }
+
+        let goals = self.goals(game);
+        let mut game = game.clone();
+        let mut turn = game.begin(game.player);
+        let mut actions = vec![];
+
+        // Take turns moving each guard, those after attackers first
+        let mut busy: Vec<(usize, Goal)> = goals
+            .iter()
+            .enumerate()
+            .filter_map(|(g, goal)| goal.map(|goal| (g, goal)))
+            .collect();
+        busy.sort_by_key(|(_, goal)| matches!(goal, Goal::Patrol(..)));
+        while turn.actions > 0 && !busy.is_empty() {
+            let mut still = vec![];
+            for (guard, goal) in busy {
+                if turn.actions > 0 && self.advance(&mut game, &mut turn, &mut actions, guard, goal)
+                {
+                    still.push((guard, goal));
+                }
+            }
+            busy = still;
+        }
+
+        actions
+    }
+}
+
+/// Tiles at the mouth of a corridor, which anyone going through it must
+/// pass, along with the way out into the open
+fn chokepoints(game: &Game) -> Vec<(Point, Direction)> {
+    let open = |pos: Point| {
+        Direction::STRAIGHT
+            .iter()
+            .filter(move |&&d| game.map.through(pos, d).is_some())
+    };
+
+    let mut points = vec![];
+    for (pos, tile) in game.map.tiles() {
+        if tile != Tile::Floor {
+            continue;
+        }
+        let ways: Vec<Direction> = open(pos).copied().collect();
+        let corridor = ways.len() == 2 && {
+            let (a, b) = (ways[0].delta(), ways[1].delta());
+            a.0 + b.0 == 0 && a.1 + b.1 == 0
+        };
+        if !corridor {
+            continue;
+        }
+        let mouth = ways.into_iter().find(|&d| {
+            game.map
+                .through(pos, d)
+                .is_some_and(|next| open(next).count() >= 3)
+        });
+        if let Some(dir) = mouth {
+            points.push((pos, dir));
+        }
+    }
+    points
+}
+
+/// Direction with the longest clear view from a point
+fn outlook(game: &Game, pos: Point) -> Direction {
+    game.map
+        .moves()
+        .iter()
+        .copied()
+        .max_by_key(|&dir| {
+            let mut next = pos;
+            let mut n = 0;
+            while let Some(p) = game.map.step(next, dir) {
+                next = p;
+                n += 1;
+            }
+            n
+        })
+        .unwrap_or(Direction::Up)
+}
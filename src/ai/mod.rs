@@ -0,0 +1,106 @@
+pub mod attacker;
+pub mod defender;
+
+use crate::path::DistanceMap;
+use crate::{Action, Direction, Game, Point, Turn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::FRAC_PI_4;
+
+/// How well computer attackers play
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    /// Heads for the target, barely going out of its way for guards
+    Easy,
+    /// Stays out of sight of any guard it knows about, waiting if it must
+    Normal,
+    /// Keeps watch ahead, slips past guards, and takes out any it can
+    /// creep up on
+    Hard,
+}
+
+/// A computer player, who sees only what a human in their place would
+pub trait Bot: Send {
+    /// Starting positions of the guards, when defending
+    fn place(&mut self, game: &Game) -> Vec<Option<(Point, Direction)>> {
+        game.guards.clone()
+    }
+
+    /// Actions to take this turn, given the player's view of the game
+    fn play(&mut self, game: &Game) -> Vec<Action>;
+}
+
+/// Computer player for the given player's role
+pub fn bot(game: &Game, player: usize) -> Box<dyn Bot> {
+    if player == game.defender {
+        Box::new(defender::Defender::new())
+    } else {
+        Box::new(attacker::Attacker::new(game.config.difficulty))
+    }
+}
+
+/// Shortest routes across the map, remembered for each destination
+#[derive(Default)]
+struct Routes {
+    cache: HashMap<Point, DistanceMap>,
+}
+
+impl Routes {
+    /// Number of moves from every tile to the destination
+    fn to(&mut self, game: &Game, dest: Point) -> &DistanceMap {
+        self.cache
+            .entry(dest)
+            .or_insert_with(|| game.map.distances(&[dest], &HashSet::new()))
+    }
+
+    /// Number of moves between two points, if there's a way at all
+    fn distance(&mut self, game: &Game, from: Point, dest: Point) -> Option<usize> {
+        self.to(game, dest).get(from)
+    }
+
+    /// Direction of the first move on a shortest route to the destination
+    fn step(&mut self, game: &Game, from: Point, dest: Point) -> Option<Direction> {
+        self.to(game, dest).downhill(&game.map, from)
+    }
+}
+
+/// Direction most nearly pointing from one point to another, out of those
+/// anyone can face in the game
+fn facing(game: &Game, from: Point, to: Point) -> Direction {
+    let dx = to.0 as i16 - from.0 as i16;
+    let dy = to.1 as i16 - from.1 as i16;
+    if game.config.diagonal {
+        // Clockwise from right, with down the screen being clockwise
+        let eighths = (dy as f64).atan2(dx as f64) / FRAC_PI_4;
+        Direction::Right.turn(eighths.round() as i8)
+    } else if dx.abs() >= dy.abs() {
+        if dx >= 0 {
+            Direction::Right
+        } else {
+            Direction::Left
+        }
+    } else if dy >= 0 {
+        Direction::Down
+    } else {
+        Direction::Up
+    }
+}
+
+/// Rotation to make to turn from one direction towards another
+fn rotation(from: Direction, to: Direction) -> Option<Action> {
+    match from.eighths(to) {
+        0 => None,
+        eighths => Some(Action::Rotate(eighths > 0)),
+    }
+}
+
+/// Take an action on the bot's own copy of the game, keeping it only if
+/// it's legal so the server will accept the whole turn
+fn act(game: &mut Game, turn: &mut Turn, actions: &mut Vec<Action>, action: Action) -> bool {
+    let legal = game.act(turn, action).is_ok();
+    if legal {
+        actions.push(action);
+    }
+    legal
+}
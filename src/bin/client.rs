@@ -6,8 +6,28 @@ use simple_logging::log_to_stderr;
 fn main() {
     log_to_stderr(LevelFilter::Info);
     let cli = Cli::parse();
+    if let Some(path) = cli.replay {
+        Replay::load(&path)
+            .and_then(|replay| {
+                Terminal::new().and_then(|ui| replay.play(&mut UserInterface::new(ui)))
+            })
+            .unwrap_or_else(|err| eprintln!("Something went wrong: \"{err}\""));
+        return;
+    }
+    let profile = Profile {
+        name: cli.name.unwrap_or_default(),
+        colour: cli.colour,
+        computer: false,
+    };
     Terminal::new()
-        .and_then(|ui| Client::new(&cli.address, UserInterface::new(ui)))
+        .and_then(|ui| {
+            let ui = UserInterface::new(ui);
+            if cli.spectate {
+                Client::spectate(&cli.address, ui)
+            } else {
+                Client::new(&cli.address, cli.token, profile, ui)
+            }
+        })
         .and_then(|mut client| client.run())
         .unwrap_or_else(|err| eprintln!("Something went wrong: \"{err}\""));
 }
@@ -6,7 +6,10 @@ use simple_logging::log_to_stderr;
 fn main() -> Result<()> {
     log_to_stderr(LevelFilter::Info);
     let cli = Cli::parse();
-    let game = Game::new(cli);
+    let mut server = match &cli.resume {
+        Some(path) => Server::resume(path, &cli.address)?,
+        None => Server::new(Game::new(cli)?)?,
+    };
 
-    Server::new(game)?.run()
+    server.run()
 }
@@ -1,5 +1,5 @@
-use crate::defaults;
-use log::info;
+use crate::{defaults, Difficulty, Generator};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use std::fs::read_to_string;
 use std::time::Duration;
@@ -13,10 +13,29 @@ struct TomlConfig {
     detection_actions: Option<u8>,
     viewcone_length: Option<u8>,
     viewcone_width: Option<u8>,
+    cover_range: Option<u8>,
+    grass_range: Option<u8>,
+    quiet_noise: Option<u8>,
+    loud_noise: Option<u8>,
     turn_time: Option<u8>,
+    time_bank: Option<u8>,
     players: Option<u8>,
     num_guards: Option<u8>,
     len: Option<u8>,
+    width: Option<u8>,
+    height: Option<u8>,
+    generator: Option<Generator>,
+    map: Option<String>,
+    replay: Option<String>,
+    save: Option<String>,
+    autosave: Option<u8>,
+    seed: Option<u64>,
+    computer_defender: Option<bool>,
+    bots: Option<u8>,
+    difficulty: Option<Difficulty>,
+    simultaneous: Option<bool>,
+    diagonal: Option<bool>,
+    spectators: Option<bool>,
 }
 
 /// Assorted configuration options (defined server-side)
@@ -28,20 +47,60 @@ pub struct Config {
     pub attacker_actions: isize,
     /// Number of actions for a defender each turn
     pub defender_actions: isize,
-    /// Number of actions inside view cone before detection
+    /// Number of moves from escaping the view cones at which an attacker is caught
     pub detection_actions: isize,
     /// Length of viewcone
     pub viewcone_length: i16,
-    /// Half-width of viewcone
+    /// Half-width of viewcone at its full length, which sets its angle
     pub viewcone_width: usize,
+    /// Distance beyond which an attacker crouched behind low cover can't be
+    /// seen (anyone standing in low cover is taken to be crouching)
+    pub cover_range: i16,
+    /// Distance beyond which an attacker in tall grass or shadow can't be seen
+    pub grass_range: i16,
+    /// Distance across open ground a move can be heard from
+    pub quiet_noise: usize,
+    /// Distance across open ground running or a door can be heard from
+    pub loud_noise: usize,
     /// Time in seconds per turn
     pub turn_time: Duration,
+    /// Extra time each player has to use up over the game when they need
+    /// longer than a turn
+    pub time_bank: Duration,
     /// Number of players
     pub players: usize,
     /// Number of guards
     pub num_guards: usize,
-    /// Length of side of map
-    pub len: usize,
+    /// Width of generated map
+    pub width: usize,
+    /// Height of generated map
+    pub height: usize,
+    /// Map generation algorithm
+    pub generator: Generator,
+    /// Map file to load instead of generating one
+    pub map: Option<String>,
+    /// File to record the game to
+    pub replay: Option<String>,
+    /// File to save the game to, so it can be resumed
+    pub save: String,
+    /// Number of turns between saves (0 to only save on request)
+    pub autosave: usize,
+    /// Seed for all randomness in the game (random if not given)
+    pub seed: Option<u64>,
+    /// Whether the computer plays the defender
+    pub computer_defender: bool,
+    /// Number of attackers played by the computer
+    pub bots: usize,
+    /// How well the computer plays attackers
+    pub difficulty: Difficulty,
+    /// Whether the attackers all take their turns at once, followed by the
+    /// defender, rather than one after another
+    pub simultaneous: bool,
+    /// Whether everyone can face and move diagonally, in eight directions
+    /// rather than four
+    pub diagonal: bool,
+    /// Whether anyone can connect to watch the game, seeing the whole map
+    pub spectators: bool,
 }
 
 impl Default for Config {
@@ -54,10 +113,28 @@ impl Default for Config {
             detection_actions: 3,
             viewcone_length: 16,
             viewcone_width: 10,
+            cover_range: 3,
+            grass_range: 6,
+            quiet_noise: 2,
+            loud_noise: 8,
             turn_time: Duration::from_secs(120),
+            time_bank: Duration::ZERO,
             players: defaults::PLAYERS,
             num_guards: 5,
-            len: 48,
+            width: 48,
+            height: 48,
+            generator: Generator::Fixed,
+            map: None,
+            replay: None,
+            save: "hanzo.save".to_string(),
+            autosave: 10,
+            seed: None,
+            computer_defender: false,
+            bots: 0,
+            difficulty: Difficulty::Normal,
+            simultaneous: false,
+            diagonal: false,
+            spectators: false,
         }
     }
 }
@@ -82,16 +159,68 @@ impl Config {
                 choose_value!(detection_actions, isize);
                 choose_value!(viewcone_length, i16);
                 choose_value!(viewcone_width, usize);
+                choose_value!(cover_range, i16);
+                choose_value!(grass_range, i16);
+                choose_value!(quiet_noise, usize);
+                choose_value!(loud_noise, usize);
                 choose_value!(players, usize);
                 choose_value!(num_guards, usize);
-                choose_value!(len, usize);
+                choose_value!(autosave, usize);
+                choose_value!(bots, usize);
+                if let Some(len) = toml.len {
+                    conf.width = len as usize;
+                    conf.height = len as usize;
+                }
+                choose_value!(width, usize);
+                choose_value!(height, usize);
                 if let Some(turn_time) = toml.turn_time {
-                    conf.turn_time = Duration::from_secs((turn_time * 60).into());
+                    conf.turn_time = Duration::from_secs(u64::from(turn_time) * 60);
+                }
+                if let Some(time_bank) = toml.time_bank {
+                    conf.time_bank = Duration::from_secs(u64::from(time_bank) * 60);
+                }
+                if let Some(generator) = toml.generator {
+                    conf.generator = generator;
+                }
+                if toml.map.is_some() {
+                    conf.map = toml.map;
+                }
+                if toml.replay.is_some() {
+                    conf.replay = toml.replay;
+                }
+                if let Some(save) = toml.save {
+                    conf.save = save;
+                }
+                if let Some(computer_defender) = toml.computer_defender {
+                    conf.computer_defender = computer_defender;
+                }
+                if let Some(difficulty) = toml.difficulty {
+                    conf.difficulty = difficulty;
+                }
+                if let Some(simultaneous) = toml.simultaneous {
+                    conf.simultaneous = simultaneous;
+                }
+                if let Some(diagonal) = toml.diagonal {
+                    conf.diagonal = diagonal;
+                }
+                if let Some(spectators) = toml.spectators {
+                    conf.spectators = spectators;
+                }
+                if toml.seed.is_some() {
+                    conf.seed = toml.seed;
                 }
             }
         } else {
             info!("hanzo.toml not found");
         }
+
+        // Someone has to join to start the game
+        let places = conf.players.saturating_sub(1);
+        if conf.computer_defender as usize + conf.bots > places {
+            warn!("Too many computer players, leaving a place for someone to join");
+            conf.computer_defender &= places > 0;
+            conf.bots = places - conf.computer_defender as usize;
+        }
         conf
     }
 }
@@ -0,0 +1,221 @@
+use crate::game::Map;
+use crate::{Direction, Point, Tile};
+use std::collections::HashSet;
+use std::f64::consts::FRAC_PI_4;
+use std::ops::RangeInclusive;
+
+/// Slope of a line out from the viewer, as a fraction
+#[derive(Clone, Copy)]
+struct Slope(i32, i32);
+
+/// Tiles at some distance out from the viewer, between two slopes
+#[derive(Clone, Copy)]
+struct Row {
+    depth: i32,
+    start: Slope,
+    end: Slope,
+}
+
+impl Row {
+    /// Columns of the tiles in the row, rounding tiles cut in half by an
+    /// edge towards the middle
+    fn columns(&self) -> RangeInclusive<i32> {
+        let Slope(n, d) = self.start;
+        let min = (2 * self.depth * n + d).div_euclid(2 * d);
+        let Slope(n, d) = self.end;
+        let max = -(-2 * self.depth * n + d).div_euclid(2 * d);
+        min..=max
+    }
+
+    /// Row one further out, between the same slopes
+    fn next(&self) -> Row {
+        Row {
+            depth: self.depth + 1,
+            ..*self
+        }
+    }
+
+    /// Whether the centre of a tile in the row lies between its slopes, so
+    /// it's only seen if it could see the viewer back
+    fn symmetric(&self, col: i32) -> bool {
+        let (Slope(n0, d0), Slope(n1, d1)) = (self.start, self.end);
+        col * d0 >= self.depth * n0 && col * d1 <= self.depth * n1
+    }
+}
+
+/// Slope to the near edge of a tile
+fn slope(depth: i32, col: i32) -> Slope {
+    Slope(2 * col - 1, 2 * depth)
+}
+
+impl Map {
+    /// Tiles that can be seen from a point, no further away than the range
+    /// and no further round than the arc (in radians) either side of the way
+    /// it faces, using symmetric shadowcasting: one floor tile can see
+    /// another exactly when the other can see it back
+    pub fn field_of_view(
+        &self,
+        origin: Point,
+        facing: Direction,
+        range: i16,
+        arc: f64,
+    ) -> Vec<(Point, Tile)> {
+        let range = range as i32;
+        let mut seen = HashSet::from([origin]);
+        let (fx, fy) = facing.delta();
+        for quadrant in Direction::STRAIGHT {
+            // Each quadrant reaches 45 degrees either side of its direction
+            let (qx, qy) = quadrant.delta();
+            let cross = (fx * qy - fy * qx) as f64;
+            let turn = cross.atan2((fx * qx + fy * qy) as f64).abs();
+            if turn - FRAC_PI_4 <= arc + 1e-9 {
+                let row = Row {
+                    depth: 1,
+                    start: Slope(-1, 1),
+                    end: Slope(1, 1),
+                };
+                self.scan(origin, quadrant, range, row, &mut seen);
+            }
+        }
+
+        let within = |(x, y): Point| {
+            let (dx, dy) = (x as i32 - origin.0 as i32, y as i32 - origin.1 as i32);
+            let ahead = dx * fx as i32 + dy * fy as i32;
+            let aside = dx * fy as i32 - dy * fx as i32;
+            let angle = (aside.abs() as f64).atan2(ahead as f64);
+            dx * dx + dy * dy <= range * range && angle <= arc + 1e-9
+        };
+        seen.into_iter()
+            .filter(|&pos| within(pos))
+            .filter_map(|(x, y)| Some(((x, y), self.at(x as usize, y as usize)?)))
+            .collect()
+    }
+
+    /// Shadowcast one row of a quadrant and those beyond it, noting every
+    /// tile seen
+    fn scan(
+        &self,
+        origin: Point,
+        quadrant: Direction,
+        range: i32,
+        mut row: Row,
+        seen: &mut HashSet<Point>,
+    ) {
+        if row.depth > range {
+            return;
+        }
+        let (qx, qy) = quadrant.delta();
+        let place = |col: i32| {
+            let x = origin.0 as i32 + row.depth * qx as i32 + col * qy.abs() as i32;
+            let y = origin.1 as i32 + row.depth * qy as i32 + col * qx.abs() as i32;
+            let pos = (u8::try_from(x).ok()?, u8::try_from(y).ok()?);
+            let tile = self.at(pos.0 as usize, pos.1 as usize)?;
+            Some((pos, tile))
+        };
+
+        // Whether the last tile blocked the view
+        let mut last = None;
+        for col in row.columns() {
+            let tile = place(col);
+            let wall = tile.is_none_or(|(_, tile)| !tile.transparent());
+            if let Some((pos, _)) = tile {
+                if wall || row.symmetric(col) {
+                    seen.insert(pos);
+                }
+            }
+            if last == Some(true) && !wall {
+                row.start = slope(row.depth, col);
+            }
+            if last == Some(false) && wall {
+                let mut next = row.next();
+                next.end = slope(row.depth, col);
+                self.scan(origin, quadrant, range, next, seen);
+            }
+            last = Some(wall);
+        }
+        if last == Some(false) {
+            self.scan(origin, quadrant, range, row.next(), seen);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{defaults, Config, Generator, Level};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+    use std::f64::consts::{FRAC_PI_2, PI};
+
+    /// Maps with plenty of walls in the way, for checking symmetry
+    fn maps() -> Vec<Map> {
+        let mut maps = vec![defaults::MAP.parse().unwrap()];
+        for generator in [Generator::Rooms, Generator::Caves] {
+            let config = Config {
+                generator,
+                width: 40,
+                height: 40,
+                ..Config::default()
+            };
+            let mut rng = ChaCha8Rng::seed_from_u64(1);
+            maps.push(Level::new(&config, &mut rng).unwrap().map);
+        }
+        maps
+    }
+
+    #[test]
+    fn symmetric() {
+        let range = 12;
+        for map in maps() {
+            let floor: Vec<Point> = map
+                .tiles()
+                .filter(|&(_, tile)| tile == Tile::Floor)
+                .map(|(pos, _)| pos)
+                .collect();
+            let sees: HashSet<(Point, Point)> = floor
+                .iter()
+                .flat_map(|&from| {
+                    map.field_of_view(from, Direction::Up, range, PI)
+                        .into_iter()
+                        .filter(|&(_, tile)| tile == Tile::Floor)
+                        .map(move |(to, _)| (from, to))
+                })
+                .collect();
+            for &(from, to) in sees.iter() {
+                assert!(
+                    sees.contains(&(to, from)),
+                    "{from:?} sees {to:?} but not the other way round"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn no_holes() {
+        let map: Map = vec![".".repeat(41); 41].join("\n").parse().unwrap();
+        let origin = (20, 20);
+        let range = 12;
+        for arc in [(8.0f64).atan2(12.0), FRAC_PI_4, FRAC_PI_2, PI] {
+            for facing in Direction::ALL {
+                let (fx, fy) = facing.delta();
+                let expected: HashSet<Point> = map
+                    .tiles()
+                    .map(|(pos, _)| pos)
+                    .filter(|&(x, y)| {
+                        let (dx, dy) = (x as i32 - 20, y as i32 - 20);
+                        let ahead = dx * fx as i32 + dy * fy as i32;
+                        let aside = dx * fy as i32 - dy * fx as i32;
+                        let angle = (aside.abs() as f64).atan2(ahead as f64);
+                        dx * dx + dy * dy <= range * range && angle <= arc + 1e-9
+                    })
+                    .collect();
+                let seen: HashSet<Point> = map
+                    .field_of_view(origin, facing, range as i16, arc)
+                    .into_iter()
+                    .map(|(pos, _)| pos)
+                    .collect();
+                assert_eq!(seen, expected, "facing {facing:?} with arc {arc}");
+            }
+        }
+    }
+}
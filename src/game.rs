@@ -1,11 +1,18 @@
-use crate::{defaults, Cli, Config, MsgToClient, MsgToServer, Result, UIBackend, UserInterface};
+use crate::{
+    Cli, Colour, Config, Level, MsgToClient, MsgToServer, Result, UIBackend, UserInterface,
+};
+use log::info;
 use rand::{
     distributions::{Distribution, Standard},
-    random, thread_rng, Rng,
+    thread_rng, Rng, SeedableRng,
 };
+use rand_chacha::ChaCha8Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
 use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Status {
@@ -15,10 +22,47 @@ pub enum Status {
     Quit,
 }
 
+/// What became of an attacker who's no longer in play
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Caught by the guards
+    Caught,
+    /// Reached their target
+    Reached,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Tile {
     Floor,
     Wall,
+    /// Somewhere a player hasn't seen, which they may as well try walking
+    /// onto but can't see through
+    Unknown,
+    /// A door, which blocks movement and sight while closed
+    Door {
+        open: bool,
+    },
+    /// Glass that can be seen through but not walked through
+    Window,
+    /// Something low to crouch behind, hiding an attacker from far off
+    Cover,
+    /// Tall grass or shadow, hiding an attacker from further off
+    Grass,
+}
+
+impl Tile {
+    /// Whether the tile can be walked on
+    pub fn walkable(self) -> bool {
+        matches!(
+            self,
+            Tile::Floor | Tile::Door { open: true } | Tile::Cover | Tile::Grass | Tile::Unknown
+        )
+    }
+
+    /// Whether the tile can be seen through
+    pub fn transparent(self) -> bool {
+        (self.walkable() || self == Tile::Window) && self != Tile::Unknown
+    }
 }
 
 impl fmt::Display for Tile {
@@ -26,137 +70,192 @@ impl fmt::Display for Tile {
         match self {
             Tile::Floor => write!(f, "."),
             Tile::Wall => write!(f, "#"),
+            Tile::Unknown => write!(f, " "),
+            Tile::Door { open: false } => write!(f, "+"),
+            Tile::Door { open: true } => write!(f, "'"),
+            Tile::Window => write!(f, "="),
+            Tile::Cover => write!(f, "_"),
+            Tile::Grass => write!(f, "\""),
         }
     }
 }
 
-impl From<char> for Tile {
-    fn from(c: char) -> Tile {
+impl TryFrom<char> for Tile {
+    type Error = char;
+
+    fn try_from(c: char) -> std::result::Result<Tile, char> {
         match c {
-            '#' => Tile::Wall,
-            _ => Tile::Floor,
+            '#' => Ok(Tile::Wall),
+            '.' => Ok(Tile::Floor),
+            '+' => Ok(Tile::Door { open: false }),
+            '\'' => Ok(Tile::Door { open: true }),
+            '=' => Ok(Tile::Window),
+            '_' => Ok(Tile::Cover),
+            '"' => Ok(Tile::Grass),
+            _ => Err(c),
         }
     }
 }
 
+/// Largest number of tiles on a side such that every tile has a Point
+pub const MAX_SIDE: usize = u8::MAX as usize + 1;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Map {
-    pub len: usize,
+    pub width: usize,
+    pub height: usize,
     pub buf: Vec<Tile>,
+    /// Whether moves can be made diagonally
+    pub diagonal: bool,
 }
 
 impl Map {
-    pub fn new(len: usize) -> Self {
-        // TODO better map generation
-        let mut buf = vec![Tile::Floor; len * len];
-        for tile in buf.iter_mut() {
-            if random() {
-                *tile = Tile::Wall;
-            }
+    /// Solid map of walls, to be carved out by a generator
+    pub fn new(width: usize, height: usize) -> Self {
+        let width = width.min(MAX_SIDE);
+        let height = height.min(MAX_SIDE);
+        let buf = vec![Tile::Wall; width * height];
+        Map {
+            width,
+            height,
+            buf,
+            diagonal: false,
         }
-
-        Map { len, buf }
     }
 
     pub fn at(&self, x: usize, y: usize) -> Option<Tile> {
-        if x < self.len && y < self.len {
-            Some(self.buf[y * self.len + x])
+        if x < self.width && y < self.height {
+            Some(self.buf[y * self.width + x])
         } else {
             None
         }
     }
 
     pub fn at_ref(&mut self, x: usize, y: usize) -> Option<&Tile> {
-        if x < self.len && y < self.len {
-            Some(&self.buf[y * self.len + x])
+        if x < self.width && y < self.height {
+            Some(&self.buf[y * self.width + x])
         } else {
             None
         }
     }
 
-    pub fn tiles(&self) -> Tiles {
+    pub fn set(&mut self, x: usize, y: usize, tile: Tile) {
+        if x < self.width && y < self.height {
+            self.buf[y * self.width + x] = tile;
+        }
+    }
+
+    pub fn tiles(&self) -> Tiles<'_> {
         Tiles {
             index: 0,
             map: self,
         }
     }
+}
 
-    /// Find a random empty (floor) tile
-    pub fn random(&self) -> Point {
-        let mut x: u8 = random();
-        let mut y: u8 = random();
+impl FromStr for Map {
+    type Err = ParseError;
 
-        loop {
-            if let Some(tile) = self.at(x as usize, y as usize) {
-                if tile == Tile::Floor {
-                    return (x, y);
+    /// Parse a rectangular grid of tiles, ignoring blank lines around it
+    fn from_str(map: &str) -> std::result::Result<Map, ParseError> {
+        let mut buf = vec![];
+        let mut width = 0;
+        let mut height = 0;
+        let mut last = 0;
+
+        for (i, line) in map.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                if height > 0 && last == 0 {
+                    last = i;
                 }
+                continue;
+            } else if last > 0 {
+                return Err(ParseError::new(last + 1, 1, "blank line inside map"));
+            } else if height == MAX_SIDE {
+                return Err(ParseError::new(
+                    i + 1,
+                    1,
+                    &format!("map is more than {MAX_SIDE} rows tall"),
+                ));
             }
-            x = random();
-            y = random();
-        }
-    }
 
-    /// Shoot a line of sight
-    pub fn bresenham(&self, start: (i16, i16), end: (i16, i16)) -> LineOfSight {
-        let (mut x0, mut y0) = start;
-        let (mut x1, mut y1) = end;
-        let mut dx = x1 as f64 - x0 as f64;
-        let mut dy = y1 as f64 - y0 as f64;
+            let mut row = 0;
+            for (j, c) in line.chars().enumerate() {
+                if j == MAX_SIDE {
+                    return Err(ParseError::new(
+                        i + 1,
+                        j + 1,
+                        &format!("map is more than {MAX_SIDE} tiles wide"),
+                    ));
+                }
+                let tile = c
+                    .try_into()
+                    .map_err(|c| ParseError::new(i + 1, j + 1, &format!("unknown tile '{c}'")))?;
+                buf.push(tile);
+                row += 1;
+            }
 
-        // Rotate dimensions?
-        let mut rotated = false;
-        if dy.abs() > dx.abs() {
-            (x0, y0) = (y0, x0);
-            (x1, y1) = (y1, x1);
-            rotated = true;
+            if height == 0 {
+                width = row;
+            } else if row != width {
+                return Err(ParseError::new(
+                    i + 1,
+                    row.min(width) + 1,
+                    &format!("row is {row} tiles wide, expected {width}"),
+                ));
+            }
+            height += 1;
         }
 
-        let mut sign = 1;
-        if x0 > x1 {
-            sign = -1;
-            x0 *= -1;
-            x1 *= -1;
+        if height == 0 {
+            Err(ParseError::new(1, 1, "map is empty"))
+        } else {
+            Ok(Map {
+                width,
+                height,
+                buf,
+                diagonal: false,
+            })
         }
-        let ystep = if y0 < y1 { 1 } else { -1 };
-
-        dx = x1 as f64 - x0 as f64;
-        dy = (y1 as f64 - y0 as f64).abs();
-        let gradient = (dx, dy);
-        let error = (dx / 2.0).floor();
+    }
+}
 
-        let current = (x0, y0);
-        let end = x1;
+/// Error in the text of a map, with its location
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub msg: String,
+}
 
-        LineOfSight {
-            current,
-            end,
-            gradient,
-            error,
-            sign,
-            ystep,
-            rotated,
-            last: Tile::Floor,
-            map: self,
+impl ParseError {
+    pub fn new(line: usize, column: usize, msg: &str) -> Self {
+        ParseError {
+            line,
+            column,
+            msg: msg.to_string(),
         }
     }
 }
 
-impl From<&str> for Map {
-    fn from(map: &str) -> Map {
-        // quick and dirty, use at your peril
-        let buf: Vec<Tile> = map
-            .chars()
-            .filter(|c| !c.is_whitespace())
-            .map(|c| c.into())
-            .collect();
-        let len: usize = (buf.len() as f64).sqrt().floor() as usize;
-        Map { len, buf }
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.msg
+        )
     }
 }
 
+impl Error for ParseError {}
+
 pub type Point = (u8, u8);
 
+/// Positions and facings of players or guards (if in play)
+type Agents = Vec<Option<(Point, Direction)>>;
+
 /// Iterator over Map's Tiles
 pub struct Tiles<'a> {
     index: usize,
@@ -168,8 +267,8 @@ impl<'a> Iterator for Tiles<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.index < self.map.buf.len() {
-            let x = self.index % self.map.len;
-            let y = self.index / self.map.len;
+            let x = self.index % self.map.width;
+            let y = self.index / self.map.width;
             self.index += 1;
             Some(((x as u8, y as u8), self.map.at(x, y).unwrap()))
         } else {
@@ -178,61 +277,72 @@ impl<'a> Iterator for Tiles<'a> {
     }
 }
 
-/// Line of sight across Map Tiles
-pub struct LineOfSight<'a> {
-    current: (i16, i16),
-    end: i16,
-    gradient: (f64, f64),
-    error: f64,
-    sign: i16,
-    ystep: i16,
-    rotated: bool,
-    last: Tile,
-    map: &'a Map,
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
 }
 
-impl<'a> Iterator for LineOfSight<'a> {
-    type Item = (Point, Tile);
+impl Direction {
+    /// Every direction, going clockwise from up
+    pub const ALL: [Direction; 8] = [
+        Direction::Up,
+        Direction::UpRight,
+        Direction::Right,
+        Direction::DownRight,
+        Direction::Down,
+        Direction::DownLeft,
+        Direction::Left,
+        Direction::UpLeft,
+    ];
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.last != Tile::Floor {
-            return None;
-        }
-
-        let new = if self.rotated {
-            (self.current.1, self.sign * self.current.0)
-        } else {
-            (self.sign * self.current.0, self.current.1)
-        };
+    /// Every direction along the grid, leaving out the diagonals
+    pub const STRAIGHT: [Direction; 4] = [
+        Direction::Up,
+        Direction::Down,
+        Direction::Left,
+        Direction::Right,
+    ];
 
-        if self.current.0 > self.end {
-            return None;
+    /// Change in position moving one tile in this direction
+    pub fn delta(&self) -> (i16, i16) {
+        match self {
+            Direction::Up => (0, -1),
+            Direction::Down => (0, 1),
+            Direction::Left => (-1, 0),
+            Direction::Right => (1, 0),
+            Direction::UpLeft => (-1, -1),
+            Direction::UpRight => (1, -1),
+            Direction::DownLeft => (-1, 1),
+            Direction::DownRight => (1, 1),
         }
+    }
 
-        let (dx, dy) = self.gradient;
-        self.current.0 += 1;
-        self.error -= dy;
-        if self.error < 0.0 {
-            self.current.1 += self.ystep;
-            self.error += dx;
-        }
+    /// Whether this direction is a diagonal
+    pub fn diagonal(&self) -> bool {
+        let (dx, dy) = self.delta();
+        dx != 0 && dy != 0
+    }
 
-        if new.0 >= 0 && new.1 >= 0 {
-            if let Some(tile) = self.map.at(new.0 as usize, new.1 as usize) {
-                self.last = tile;
-                return Some(((new.0 as u8, new.1 as u8), tile));
-            }
-        }
-        None
+    /// Direction after some eighths of a turn, clockwise if positive
+    pub fn turn(&self, eighths: i8) -> Direction {
+        let i = Direction::ALL.iter().position(|d| d == self).unwrap_or(0);
+        Direction::ALL[(i as i8 + eighths).rem_euclid(8) as usize]
     }
-}
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Direction {
-    Up,
-    Down,
-    Left,
-    Right,
+    /// Eighths of a turn from this direction to another, clockwise if
+    /// positive, and anticlockwise when they're opposite
+    pub fn eighths(&self, to: Direction) -> i8 {
+        let i = Direction::ALL.iter().position(|d| d == self).unwrap_or(0);
+        let j = Direction::ALL.iter().position(|&d| d == to).unwrap_or(0);
+        (j as i8 - i as i8 + 4).rem_euclid(8) - 4
+    }
 }
 
 impl Distribution<Direction> for Standard {
@@ -246,6 +356,49 @@ impl Distribution<Direction> for Standard {
     }
 }
 
+/// How a player is known to everyone else
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Profile {
+    pub name: String,
+    pub colour: Option<Colour>,
+    /// Played by the computer rather than a person
+    pub computer: bool,
+}
+
+/// A single action taken by a player during their turn
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Move one tile (the selected guard, for the defender)
+    Move(Direction),
+    /// Move two tiles at once, loudly (attackers only)
+    Run(Direction),
+    /// Rotate field-of-view, clockwise or not
+    Rotate(bool),
+    /// Open or close the door next to them in a direction (the selected
+    /// guard, for the defender)
+    Toggle(Direction),
+    /// Select the next guard (defender only, costs nothing)
+    Cycle,
+    /// Do nothing
+    Wait,
+    /// Leave the game
+    Quit,
+}
+
+/// Progress of a player through their turn
+pub struct Turn {
+    pub player: usize,
+    pub defender: bool,
+    /// Selected guard
+    pub guard: usize,
+    /// Actions remaining
+    pub actions: isize,
+    /// Has the turn been cut short?
+    pub done: bool,
+    /// Tiles the player knew of when they planned the turn
+    pub known: HashMap<Point, Tile>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Game {
     pub address: String,
@@ -257,67 +410,272 @@ pub struct Game {
     pub guards: Vec<Option<(Point, Direction)>>,
     pub targets: Vec<Option<Point>>,
     pub map: Map,
+    pub profiles: Vec<Profile>,
+    /// Number of attackers and guards still in play (some may be hidden)
+    pub remaining: (usize, usize),
+    /// What became of each attacker no longer in play
+    pub outcomes: Vec<Option<Outcome>>,
+    /// Noises the guards have heard since the defender's last turn, as the
+    /// guard and the way it came from
+    pub heard: Vec<(usize, Direction)>,
+    /// Whether the guards' starting positions are settled, after which they
+    /// can't be placed again
+    pub placed: bool,
+    /// Tiles each attacker has seen, as they last saw them
+    pub memory: Vec<HashMap<Point, Tile>>,
+    /// Source of all randomness in the game, seeded from the config
+    pub rng: ChaCha8Rng,
 }
 
 impl Game {
-    pub fn new(cli: Cli) -> Self {
+    /// New game, whose defender is assigned once the players are known
+    pub fn new(cli: Cli) -> Result<Self> {
         let address = cli.address;
-        let config = Config::new();
-        let player = 0;
+        let mut config = Config::new();
 
-        // Currently use test defaults
-        let map = defaults::MAP.into();
-        let mut positions = defaults::POSITIONS.to_vec();
-        let mut targets = defaults::TARGETS.to_vec();
-        let guards = defaults::GUARDS.to_vec();
+        // Pick a seed if there isn't one, so the game can be reproduced
+        let seed = cli
+            .seed
+            .or(config.seed)
+            .unwrap_or_else(|| thread_rng().gen());
+        info!("Seed: {}", seed);
+        config.seed = Some(seed);
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
 
-        let mut rng = thread_rng();
-        let defender = rng.gen_range(0..config.players);
-        positions[defender] = None;
-        targets[defender] = None;
+        let level = match &config.map {
+            Some(path) => Level::load(path, &config)?,
+            None => Level::new(&config, &mut rng)?,
+        };
+        Ok(Game::from_level(address, config, level, rng))
+    }
 
-        Game {
+    /// New game on the given level, taking any further randomness from the
+    /// given source
+    pub fn from_level(address: String, config: Config, level: Level, rng: ChaCha8Rng) -> Self {
+        let Level {
+            mut map,
+            positions,
+            targets,
+            guards,
+        } = level;
+        let player = 0;
+
+        map.diagonal = config.diagonal;
+        let remaining = (positions.len(), guards.len());
+        let outcomes = vec![None; positions.len()];
+        let memory = vec![HashMap::new(); positions.len()];
+
+        let mut game = Game {
             address,
             config,
             quit: Status::Running,
-            defender,
+            defender: 0,
             player,
             positions,
             guards,
             targets,
             map,
+            profiles: vec![],
+            remaining,
+            outcomes,
+            heard: vec![],
+            placed: false,
+            memory,
+            rng,
+        };
+        game.survey();
+        game
+    }
+
+    /// Name of a player
+    pub fn name(&self, player: usize) -> String {
+        match self.profiles.get(player) {
+            Some(profile) => profile.name.clone(),
+            None => format!("Player {}", player + 1),
+        }
+    }
+
+    /// Chosen colour of a player, if any
+    pub fn colour(&self, player: usize) -> Option<Colour> {
+        self.profiles.get(player).and_then(|p| p.colour)
+    }
+
+    /// Make one of the players the defender, taking their attacker out of play
+    pub fn assign(&mut self, defender: usize) {
+        self.defender = defender;
+        self.positions[defender] = None;
+        self.targets[defender] = None;
+        self.remaining = self.remaining();
+        self.survey();
+    }
+
+    /// Start the defender off knowing the whole map, and everyone else what
+    /// they can see from where they are
+    fn survey(&mut self) {
+        for player in 0..self.memory.len() {
+            self.memory[player] = if player == self.defender {
+                self.map.tiles().collect()
+            } else {
+                self.seen(player).into_iter().collect()
+            };
         }
     }
 
     /// Check for victory
     pub fn victory(&mut self) {
-        // Attacker victory by objective
-        let mut v = true;
-        for i in 0..self.positions.len() {
-            if let Some((pos, _)) = self.positions[i] {
-                if Some(pos) != self.targets[i] {
-                    v = false;
-                }
+        // Attacker victory by eliminating every guard
+        if self.guards.iter().all(|x| x.is_none()) {
+            self.quit = Status::AttackerVictory;
+            return;
+        }
+
+        // Otherwise it's over once no attackers are left in play, and the
+        // defender only wins if they caught every one
+        if self.positions.iter().any(|x| x.is_some()) {
+            return;
+        }
+        let caught = (0..self.outcomes.len())
+            .filter(|&i| i != self.defender)
+            .all(|i| self.outcomes[i] == Some(Outcome::Caught));
+        self.quit = if caught {
+            Status::DefenderVictory
+        } else {
+            Status::AttackerVictory
+        };
+    }
+
+    /// Snapshot of the game as known to the given player
+    pub fn view(&self, player: usize) -> Game {
+        let mut game = self.clone();
+        game.player = player;
+        (game.positions, game.guards) = self.visible_to(player);
+        for (i, target) in game.targets.iter_mut().enumerate() {
+            if i != player {
+                *target = None;
+            }
+        }
+        game.remaining = self.remaining();
+        if player != self.defender {
+            game.heard.clear();
+        }
+
+        // Nobody knows what's become of the map out of their sight
+        game.map.buf.fill(Tile::Unknown);
+        for ((x, y), tile) in self.known(player) {
+            game.map.set(x as usize, y as usize, tile);
+        }
+        for (i, memory) in game.memory.iter_mut().enumerate() {
+            if i != player {
+                memory.clear();
             }
         }
 
-        // Attacker victory by objective
-        let u = self.guards.iter().filter(|&x| x.is_none()).count() == self.guards.len();
+        // The seed would give away the rest of the map's secrets
+        game.config.seed = None;
+        game.rng = ChaCha8Rng::seed_from_u64(player as u64);
+        game
+    }
 
-        if u || v {
-            self.quit = Status::AttackerVictory;
-            return;
+    /// Tiles the given player can see, through their guards' eyes for the
+    /// defender
+    fn seen(&self, player: usize) -> Vec<(Point, Tile)> {
+        if player == self.defender {
+            self.guards
+                .iter()
+                .flat_map(|&guard| self.view_cone(guard))
+                .collect()
+        } else {
+            self.view_cone(self.positions[player])
+        }
+    }
+
+    /// Tiles the given player knows of, as they last saw them
+    fn known(&self, player: usize) -> HashMap<Point, Tile> {
+        let mut known = self.memory.get(player).cloned().unwrap_or_default();
+        known.extend(self.seen(player));
+        known
+    }
+
+    /// Note everything the given player can see as they see it now
+    fn remember(&mut self, player: usize) {
+        let seen = self.seen(player);
+        if let Some(memory) = self.memory.get_mut(player) {
+            memory.extend(seen);
+        }
+    }
+
+    /// Positions of players and guards the given player can see
+    fn visible_to(&self, player: usize) -> (Agents, Agents) {
+        let cone: HashSet<Point> = if player == self.defender {
+            self.cones()
+        } else {
+            self.view_cone(self.positions[player])
+                .into_iter()
+                .map(|(pos, _)| pos)
+                .collect()
+        };
+        let seen = |x: &Option<(Point, Direction)>| x.filter(|(pos, _)| cone.contains(pos));
+
+        let positions = self
+            .positions
+            .iter()
+            .enumerate()
+            .map(|(i, x)| if i == player { *x } else { seen(x) })
+            .collect();
+        let guards = if player == self.defender {
+            self.guards.clone()
+        } else {
+            self.guards.iter().map(seen).collect()
+        };
+        (positions, guards)
+    }
+
+    /// Number of attackers and guards still in play
+    fn remaining(&self) -> (usize, usize) {
+        (
+            self.positions.iter().filter(|&x| x.is_some()).count(),
+            self.guards.iter().filter(|&x| x.is_some()).count(),
+        )
+    }
+
+    /// Players taking their turn when it's the given player's turn, which
+    /// is every attacker still in play at once if they move together
+    pub fn movers(&self, current: usize) -> Vec<usize> {
+        if self.config.simultaneous && current != self.defender {
+            (0..self.positions.len())
+                .filter(|&i| self.positions[i].is_some())
+                .collect()
+        } else {
+            vec![current]
         }
+    }
 
-        // Defender victory by elimination
-        if self.positions.iter().filter(|&x| x.is_none()).count() == self.guards.len() {
-            self.quit = Status::DefenderVictory;
+    /// Whose turn it is after the given player's
+    pub fn next(&self, current: usize) -> usize {
+        let players = self.config.players;
+        if !self.config.simultaneous {
+            (current + 1) % players
+        } else if current == self.defender {
+            (0..players)
+                .find(|&i| i != self.defender)
+                .unwrap_or(current)
+        } else {
+            self.defender
+        }
+    }
+
+    /// Name of whoever's turn it is
+    pub fn whose(&self, current: usize) -> String {
+        if self.config.simultaneous && current != self.defender {
+            "the attackers".to_string()
+        } else {
+            self.name(current)
         }
     }
 
     /// Server-side turn processing
     pub fn turn(&self, player: usize, current: usize) -> MsgToClient {
-        let turn = player == current;
+        let turn = self.movers(current).contains(&player);
         let defender = player == self.defender;
         let quit = if (defender && self.quit == Status::AttackerVictory)
             || (!defender && self.quit == Status::DefenderVictory)
@@ -327,207 +685,696 @@ impl Game {
             self.quit
         };
 
+        let (positions, guards) = self.visible_to(player);
+        let heard = if defender { self.heard.clone() } else { vec![] };
         MsgToClient {
             turn,
+            current,
             defender,
+            positions,
+            guards,
+            tiles: self.known(player).into_iter().collect(),
+            heard,
+            remaining: self.remaining(),
+            outcomes: self.outcomes.clone(),
+            time: self.config.turn_time,
+            quit,
+            error: None,
+        }
+    }
+
+    /// Server-side turn processing for spectators, who see everything
+    pub fn spectate(&self, current: usize) -> MsgToClient {
+        MsgToClient {
+            turn: false,
+            current,
+            defender: false,
             positions: self.positions.clone(),
             guards: self.guards.clone(),
-            quit,
+            tiles: self
+                .map
+                .tiles()
+                .filter(|(_, tile)| matches!(tile, Tile::Door { .. }))
+                .collect(),
+            heard: self.heard.clone(),
+            remaining: self.remaining(),
+            outcomes: self.outcomes.clone(),
+            time: self.config.turn_time,
+            quit: self.quit,
+            error: None,
         }
     }
 
-    /// Server-side turn processing
-    pub fn update(&mut self, msg: MsgToServer, current: usize) {
-        self.positions[current] = msg.new;
-        self.guards = msg.guards;
+    /// Server-side turn processing, replaying the player's actions
+    pub fn update(&mut self, msg: MsgToServer, current: usize) -> std::result::Result<(), String> {
+        match msg {
+            MsgToServer::Place(guards) if current == self.defender => self.place(guards),
+            MsgToServer::Place(_) => Err("only the defender can place guards".to_string()),
+            MsgToServer::Lobby(_) => Err("game has already started".to_string()),
+            MsgToServer::Turn(actions) => {
+                // Only keep the outcome if every action was legal
+                let mut game = self.clone();
+                let mut turn = game.begin(current);
+                if turn.defender {
+                    game.heard.clear();
+                }
+                for action in actions {
+                    // The client can't always know when its turn is cut short
+                    if turn.done {
+                        break;
+                    }
+                    game.act(&mut turn, action)?;
+                }
+                *self = game;
+                Ok(())
+            }
+        }
+    }
+
+    /// Server-side processing of the turns of everyone who took theirs at
+    /// once, returning why any of them were refused
+    ///
+    /// Everyone takes their next action at the same time, so nobody gets a
+    /// head start: when several move onto the same tile the first of them
+    /// in turn order gets there and the rest bump into them, and anyone
+    /// caught is caught before they can act again.
+    pub fn update_all(&mut self, msgs: Vec<(usize, MsgToServer)>) -> Vec<(usize, String)> {
+        let mut errors = vec![];
+        let mut plans = vec![];
+        for (player, msg) in msgs {
+            // Each turn must be legal taken on its own
+            let mut alone = self.clone();
+            match alone.update(msg.clone(), player) {
+                Err(err) => errors.push((player, err)),
+                Ok(()) => match msg {
+                    MsgToServer::Turn(actions) => plans.push((self.begin(player), actions)),
+                    // Anything else only ever comes from one player at a time
+                    _ => *self = alone,
+                },
+            }
+        }
+
+        plans.sort_by_key(|(turn, _)| turn.player);
+        if plans.iter().any(|(turn, _)| turn.defender) {
+            self.heard.clear();
+        }
+
+        for round in 0.. {
+            let acting: Vec<(usize, Action)> = plans
+                .iter()
+                .enumerate()
+                .filter(|(_, (turn, _))| !turn.done && turn.actions > 0)
+                .filter_map(|(i, (_, actions))| Some((i, *actions.get(round)?)))
+                .collect();
+            if acting.is_empty() {
+                break;
+            }
+            let dests: Vec<Option<Point>> = acting
+                .iter()
+                .map(|&(i, action)| {
+                    let (dir, steps) = match action {
+                        Action::Move(dir) => (dir, 1),
+                        Action::Run(dir) => (dir, 2),
+                        _ => return None,
+                    };
+                    let (mut pos, _) = self.positions[plans[i].0.player]?;
+                    for _ in 0..steps {
+                        pos = self.map.step(pos, dir)?;
+                    }
+                    Some(pos)
+                })
+                .collect();
+
+            for (k, (&(i, action), dest)) in acting.iter().zip(dests.iter()).enumerate() {
+                let bumped = dest.is_some() && dests[..k].contains(dest);
+                let action = if bumped { Action::Wait } else { action };
+                // A move that can't be made after a bump is lost too
+                let turn = &mut plans[i].0;
+                if self.apply(turn, action).is_err() {
+                    self.apply(turn, Action::Wait).ok();
+                }
+            }
+
+            self.detect();
+            for (turn, _) in plans.iter_mut() {
+                if !turn.defender && self.positions[turn.player].is_none() {
+                    turn.done = true;
+                }
+            }
+            for player in 0..self.positions.len() {
+                self.remember(player);
+            }
+        }
+        errors
+    }
+
+    /// Check and set the guards' starting positions chosen by the defender
+    fn place(
+        &mut self,
+        guards: Vec<Option<(Point, Direction)>>,
+    ) -> std::result::Result<(), String> {
+        if self.placed {
+            return Err("guards have already been placed".to_string());
+        }
+        if guards.len() != self.config.num_guards {
+            return Err(format!("expected {} guards", self.config.num_guards));
+        }
+        for (i, guard) in guards.iter().enumerate() {
+            match guard {
+                Some((pos, _)) if self.map.open(*pos) => {
+                    if guards[..i].iter().flatten().any(|g| g.0 == *pos) {
+                        return Err(format!("guard {i} is on the same tile as another"));
+                    }
+                }
+                _ => return Err(format!("guard {i} is not somewhere they can stand")),
+            }
+        }
+        self.guards = guards;
+        self.placed = true;
+        Ok(())
+    }
+
+    /// Start a player's turn
+    pub fn begin(&self, player: usize) -> Turn {
+        let defender = player == self.defender;
+        Turn {
+            player,
+            defender,
+            guard: self.guards.iter().position(|&x| x.is_some()).unwrap_or(0),
+            actions: if defender {
+                self.config.defender_actions
+            } else {
+                self.config.attacker_actions
+            },
+            done: !defender && self.positions[player].is_none(),
+            known: self.memory.get(player).cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Apply a single action as part of a player's turn
+    pub fn act(&mut self, turn: &mut Turn, action: Action) -> std::result::Result<(), String> {
+        self.apply(turn, action)?;
+
+        // Check for attacker elimination, whoever's turn it is
+        self.detect();
+        if !turn.defender && self.positions[turn.player].is_none() {
+            turn.done = true;
+        }
+
+        for player in 0..self.positions.len() {
+            self.remember(player);
+        }
+
+        Ok(())
+    }
+
+    /// Apply a single action, without checking whether anyone's been caught
+    fn apply(&mut self, turn: &mut Turn, action: Action) -> std::result::Result<(), String> {
+        if turn.done || turn.actions <= 0 {
+            return Err("no actions remaining this turn".to_string());
+        }
+
+        let legal = match action {
+            Action::Quit => {
+                self.quit = Status::Quit;
+                turn.actions = 0;
+                return Ok(());
+            }
+            Action::Cycle => {
+                if !turn.defender {
+                    return Err("only the defender has guards to cycle through".to_string());
+                }
+                if self.guards.iter().all(|x| x.is_none()) {
+                    return Err("no guards remaining".to_string());
+                }
+                loop {
+                    turn.guard = (turn.guard + 1) % self.guards.len();
+                    if self.guards[turn.guard].is_some() {
+                        return Ok(());
+                    }
+                }
+            }
+            Action::Wait => true,
+            Action::Move(dir) => self.walk(turn, dir),
+            Action::Run(dir) => !turn.defender && self.run(turn, dir),
+            Action::Rotate(clockwise) => {
+                if turn.defender {
+                    self.rotate_guard(turn.guard, clockwise)
+                } else {
+                    self.rotate_player(turn.player, clockwise)
+                }
+            }
+            Action::Toggle(dir) => self.toggle(turn, dir),
+        };
+        if !legal {
+            return Err(format!("illegal action {action:?}"));
+        }
+        turn.actions -= 1;
+
+        if !turn.defender {
+            let loudness = match action {
+                Action::Move(_) => self.config.quiet_noise,
+                Action::Run(_) | Action::Toggle(_) => self.config.loud_noise,
+                _ => 0,
+            };
+            self.arrive(turn.player, loudness);
+        }
+
+        Ok(())
+    }
+
+    /// Deal with an attacker having made some noise where they are: any
+    /// guard in earshot hears it, any guard there is taken out, and they're
+    /// out of play once at their target
+    fn arrive(&mut self, player: usize, loudness: usize) {
+        let Some((pos, _)) = self.positions[player] else {
+            return;
+        };
+        if loudness > 0 {
+            self.listen(pos, loudness);
+        }
+
+        // Check for guard elimination
+        for guard in self.guards.iter_mut() {
+            if guard.filter(|(g, _)| *g == pos).is_some() {
+                *guard = None;
+            }
+        }
+
+        // Check for reaching the target
+        if Some(pos) == self.targets[player] {
+            self.positions[player] = None;
+            self.outcomes[player] = Some(Outcome::Reached);
+        }
+    }
+
+    /// Note every guard within earshot of a noise made at a point
+    fn listen(&mut self, from: Point, loudness: usize) {
+        let noise = self.map.noise(from, loudness);
+        for (i, guard) in self.guards.iter().enumerate() {
+            if let Some(&dir) = guard.and_then(|(pos, _)| noise.get(&pos)) {
+                if !self.heard.contains(&(i, dir)) {
+                    self.heard.push((i, dir));
+                }
+            }
+        }
+    }
+
+    /// Tiles on which any of the guards would see an attacker
+    fn cones(&self) -> HashSet<Point> {
+        self.guards
+            .iter()
+            .flat_map(|&guard| self.spots(guard))
+            .collect()
+    }
+
+    /// Catch every attacker who'd take too many moves to get out of sight
+    fn detect(&mut self) {
+        let cone = self.cones();
+        let seen: Vec<(usize, Point)> = self
+            .positions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, x)| x.map(|(pos, _)| (i, pos)))
+            .filter(|(_, pos)| cone.contains(pos))
+            .collect();
+        if seen.is_empty() {
+            return;
+        }
+
+        let escape = self.map.escape(&cone);
+        for (player, pos) in seen {
+            let moves = escape.get(pos).unwrap_or(usize::MAX);
+            if moves >= self.config.detection_actions.max(1) as usize {
+                self.positions[player] = None;
+                self.outcomes[player] = Some(Outcome::Caught);
+            }
+        }
+    }
+
+    /// Client-side update from the server
+    pub fn observe(&mut self, msg: &MsgToClient) {
+        self.positions = msg.positions.clone();
+        self.guards = msg.guards.clone();
+        for &((x, y), tile) in msg.tiles.iter() {
+            self.map.set(x as usize, y as usize, tile);
+        }
+        // Moves are judged by what's been seen, as the server remembers it
+        if let Some(memory) = self.memory.get_mut(self.player) {
+            memory.extend(msg.tiles.iter().copied());
+        }
+        self.heard = msg.heard.clone();
+        self.remaining = msg.remaining;
+        self.outcomes = msg.outcomes.clone();
         self.quit = msg.quit;
     }
 
+    /// What's become of anyone since the last update from the server
+    fn news(&self, msg: &MsgToClient) -> Vec<String> {
+        msg.outcomes
+            .iter()
+            .zip(self.outcomes.iter())
+            .enumerate()
+            .filter(|(_, (new, old))| new != old)
+            .filter_map(|(i, (new, _))| {
+                let name = if i == self.player {
+                    "You".to_string()
+                } else {
+                    self.name(i)
+                };
+                match new {
+                    Some(Outcome::Caught) => Some(format!("{name} got caught!")),
+                    Some(Outcome::Reached) => Some(format!("{name} reached the target!")),
+                    None => None,
+                }
+            })
+            .collect()
+    }
+
     /// Client-side turn processing
     pub fn display<T: UIBackend>(
         &mut self,
         ui: &mut UserInterface<T>,
         msg: &MsgToClient,
     ) -> Result<()> {
-        self.positions = msg.positions.clone();
-        self.guards = msg.guards.clone();
-        self.quit = msg.quit;
+        let mut news = self.news(msg);
+        self.observe(msg);
+        if let Some(error) = &msg.error {
+            news.push(format!("Server refused your last turn: {error}."));
+        }
+        if !news.is_empty() {
+            ui.notify(&news.join(" "));
+        }
+        ui.wait_for(&self.whose(msg.current));
         ui.display(self, msg.defender)?;
         Ok(())
     }
 
+    /// Client-side turn processing for spectators
+    pub fn watch<T: UIBackend>(
+        &mut self,
+        ui: &mut UserInterface<T>,
+        msg: &MsgToClient,
+    ) -> Result<()> {
+        let news = self.news(msg);
+        self.observe(msg);
+        if !news.is_empty() {
+            ui.notify(&news.join(" "));
+        }
+        ui.wait_for(&self.whose(msg.current));
+        ui.spectate(self)
+    }
+
     /// Client-side turn processing
     pub fn play<T: UIBackend>(
         &mut self,
         defender: bool,
+        time: Duration,
         ui: &mut UserInterface<T>,
     ) -> Result<MsgToServer> {
-        ui.input(self, defender)?;
-        Ok(MsgToServer {
-            new: self.positions[self.player],
-            guards: self.guards.clone(),
-            quit: self.quit,
-        })
+        let actions = ui.input(self, defender, time)?;
+        Ok(MsgToServer::Turn(actions))
     }
 
     /// Guard placement for defending player
     pub fn place_guards<T: UIBackend>(&mut self, ui: &mut UserInterface<T>) -> Result<MsgToServer> {
         ui.place_guards(self)?;
-        Ok(MsgToServer {
-            new: self.positions[self.player],
-            guards: self.guards.clone(),
-            quit: self.quit,
-        })
+        Ok(MsgToServer::Place(self.guards.clone()))
     }
 
-    /// Tiles within guard's line-of-sight
+    /// Tiles within guard's line-of-sight, reaching `viewcone_length` tiles
+    /// ahead and spreading to `viewcone_width` either side at that distance
     pub fn view_cone(&self, start: Option<(Point, Direction)>) -> Vec<(Point, Tile)> {
-        fn y(r: i16, x: usize) -> i16 {
-            let xf = x as f64;
-            let rf = r as f64;
-            (rf.powi(2) - xf.powi(2)).powf(0.5) as i16
-        }
-
-        let mut cone = HashSet::new();
-        let mut ends = HashSet::new();
-        if let Some((pos, dir)) = start {
-            // Determine edge of cone
-            for i in 0..self.config.viewcone_width {
-                for j in 0..2 {
-                    match dir {
-                        Direction::Up => {
-                            ends.insert((
-                                pos.0 as i16 + i as i16,
-                                pos.1 as i16 - y(self.config.viewcone_length, i) + j,
-                            ));
-                            ends.insert((
-                                pos.0 as i16 - i as i16,
-                                pos.1 as i16 - y(self.config.viewcone_length, i) + j,
-                            ));
-                        }
-                        Direction::Right => {
-                            ends.insert((
-                                pos.0 as i16 + y(self.config.viewcone_length, i) - j,
-                                pos.1 as i16 + i as i16,
-                            ));
-                            ends.insert((
-                                pos.0 as i16 + y(self.config.viewcone_length, i) - j,
-                                pos.1 as i16 - i as i16,
-                            ));
-                        }
-                        Direction::Down => {
-                            ends.insert((
-                                pos.0 as i16 + i as i16,
-                                pos.1 as i16 + y(self.config.viewcone_length, i) - j,
-                            ));
-                            ends.insert((
-                                pos.0 as i16 - i as i16,
-                                pos.1 as i16 + y(self.config.viewcone_length, i) - j,
-                            ));
-                        }
-                        Direction::Left => {
-                            ends.insert((
-                                pos.0 as i16 - y(self.config.viewcone_length, i) + j,
-                                pos.1 as i16 + i as i16,
-                            ));
-                            ends.insert((
-                                pos.0 as i16 - y(self.config.viewcone_length, i) + j,
-                                pos.1 as i16 - i as i16,
-                            ));
-                        }
-                    }
-                }
-            }
+        let Some((pos, dir)) = start else {
+            return vec![];
+        };
+        let length = self.config.viewcone_length;
+        let arc = (self.config.viewcone_width as f64).atan2(length as f64);
+        self.map.field_of_view(pos, dir, length, arc)
+    }
 
-            // For each end-point shoot a line-of-sight
-            for end in ends.iter() {
-                for p in self.map.bresenham((pos.0 as i16, pos.1 as i16), *end) {
-                    cone.insert(p);
-                }
-            }
-        }
-        Vec::from_iter(cone)
+    /// Tiles on which a guard would see an attacker, who can't be seen
+    /// from too far off in cover or tall grass
+    pub fn spots(&self, guard: Option<(Point, Direction)>) -> Vec<Point> {
+        let Some(((x, y), _)) = guard else {
+            return vec![];
+        };
+        self.view_cone(guard)
+            .into_iter()
+            .filter(|&((px, py), tile)| {
+                let range = match tile {
+                    Tile::Cover => self.config.cover_range,
+                    Tile::Grass => self.config.grass_range,
+                    _ => return true,
+                } as i32;
+                let (dx, dy) = (px as i32 - x as i32, py as i32 - y as i32);
+                dx * dx + dy * dy <= range * range
+            })
+            .map(|(pos, _)| pos)
+            .collect()
     }
 
-    /// Is the player visible?
-    pub fn visible(&self, player: usize) -> bool {
-        if let Some((player, _)) = self.positions[player] {
-            for guard in self.guards.iter() {
-                for (view, _) in self.view_cone(*guard).iter() {
-                    if *view == player {
-                        return true;
-                    }
-                }
+    /// Move player position
+    pub fn move_player(&mut self, player: usize, dir: Direction) -> bool {
+        if let Some((pos, facing)) = self.positions[player] {
+            if let Some(next) = self.map.step(pos, dir) {
+                self.positions[player] = Some((next, facing));
+                return true;
             }
         }
         false
     }
 
-    /// Move player position
-    pub fn move_player(&mut self, dx: i16, dy: i16) {
-        if let Some(((x, y), dir)) = self.positions[self.player] {
-            let x2 = (x as i16) + dx;
-            let y2 = (y as i16) + dy;
-            if let Some(tile) = self.map.at(x2 as usize, y2 as usize) {
-                if tile == Tile::Floor {
-                    self.positions[self.player] = Some(((x2 as u8, y2 as u8), dir));
-                }
+    /// The attacker or selected guard taking the turn
+    fn agent(&self, turn: &Turn) -> Option<(Point, Direction)> {
+        if turn.defender {
+            self.guards.get(turn.guard).copied().flatten()
+        } else {
+            self.positions[turn.player]
+        }
+    }
+
+    /// Move an attacker or guard a step, or have them walk into something
+    /// they didn't know was in the way
+    fn walk(&mut self, turn: &mut Turn, dir: Direction) -> bool {
+        let Some((pos, _)) = self.agent(turn) else {
+            return false;
+        };
+        let moved = if turn.defender {
+            self.move_guard(turn.guard, dir)
+        } else {
+            self.move_player(turn.player, dir)
+        };
+        if moved {
+            return true;
+        }
+        if !self.expects(turn, pos, dir) {
+            return false;
+        }
+        self.bump(turn, pos, dir);
+        true
+    }
+
+    /// Move an attacker two tiles at once, dealing with the tile passed
+    /// through on the way as if they'd stopped there
+    fn run(&mut self, turn: &mut Turn, dir: Direction) -> bool {
+        let Some((pos, _)) = self.positions[turn.player] else {
+            return false;
+        };
+        let Some(next) = self.map.step(pos, dir) else {
+            return self.walk(turn, dir);
+        };
+        if self.map.step(next, dir).is_none() && !self.expects(turn, next, dir) {
+            return false;
+        }
+        self.move_player(turn.player, dir);
+        self.arrive(turn.player, self.config.loud_noise);
+        self.detect();
+        self.remember(turn.player);
+
+        // Anyone caught or at their target on the way stops there
+        if self.positions[turn.player].is_some() && !self.move_player(turn.player, dir) {
+            self.bump(turn, next, dir);
+        }
+        true
+    }
+
+    /// Open or close the door ahead of an attacker or guard, unless it isn't
+    /// as they last saw it, in which case they stop to take it in instead
+    fn toggle(&mut self, turn: &mut Turn, dir: Direction) -> bool {
+        let Some(((x, y), _)) = self.agent(turn) else {
+            return false;
+        };
+        if dir.diagonal() && !self.map.diagonal {
+            return false;
+        }
+        let (dx, dy) = dir.delta();
+        let (Ok(nx), Ok(ny)) = (u8::try_from(x as i16 + dx), u8::try_from(y as i16 + dy)) else {
+            return false;
+        };
+        let tile = self.map.at(nx as usize, ny as usize);
+        let door = matches!(tile, Some(Tile::Door { .. }));
+        if door
+            && turn
+                .known
+                .get(&(nx, ny))
+                .is_some_and(|&known| Some(known) != tile)
+        {
+            self.bump(turn, (x, y), dir);
+            return true;
+        }
+        self.toggle_door((x, y), dir)
+    }
+
+    /// Whether a player would have thought they could step from a point,
+    /// going by what they'd seen of the map when planning their turn
+    fn expects(&self, turn: &Turn, from: Point, dir: Direction) -> bool {
+        let open = |(x, y): Point| match turn.known.get(&(x, y)) {
+            Some(tile) => tile.walkable(),
+            None => self.map.at(x as usize, y as usize).is_some(),
+        };
+        self.map.reach(from, dir, open).is_some()
+    }
+
+    /// Stop someone who walked into something in the way, who now knows
+    /// it's there
+    fn bump(&mut self, turn: &mut Turn, (x, y): Point, dir: Direction) {
+        let (dx, dy) = dir.delta();
+        for (dx, dy) in [(dx, dy), (dx, 0), (0, dy)] {
+            let (Ok(x), Ok(y)) = (u8::try_from(x as i16 + dx), u8::try_from(y as i16 + dy)) else {
+                continue;
+            };
+            if let Some(tile) = self.map.at(x as usize, y as usize) {
+                self.memory[turn.player].insert((x, y), tile);
             }
         }
+        turn.done = true;
     }
 
     /// Move player direction
-    pub fn rotate_player(&mut self, clockwise: bool) {
-        if let Some((pos, dir)) = self.positions[self.player] {
-            self.positions[self.player] = if clockwise {
-                match dir {
-                    Direction::Up => Some((pos, Direction::Right)),
-                    Direction::Right => Some((pos, Direction::Down)),
-                    Direction::Down => Some((pos, Direction::Left)),
-                    Direction::Left => Some((pos, Direction::Up)),
-                }
-            } else {
-                match dir {
-                    Direction::Up => Some((pos, Direction::Left)),
-                    Direction::Right => Some((pos, Direction::Up)),
-                    Direction::Down => Some((pos, Direction::Right)),
-                    Direction::Left => Some((pos, Direction::Down)),
-                }
-            }
+    pub fn rotate_player(&mut self, player: usize, clockwise: bool) -> bool {
+        if let Some((pos, dir)) = self.positions[player] {
+            self.positions[player] = Some((pos, dir.turn(self.rotation(clockwise))));
+            return true;
         }
+        false
     }
 
     /// Move guard position
-    pub fn move_guard(&mut self, guard: usize, dx: i16, dy: i16) {
-        if let Some(((x, y), dir)) = self.guards[guard] {
-            let x2 = (x as i16) + dx;
-            let y2 = (y as i16) + dy;
-            if let Some(tile) = self.map.at(x2 as usize, y2 as usize) {
-                if tile == Tile::Floor {
-                    self.guards[guard] = Some(((x2 as u8, y2 as u8), dir));
-                }
+    pub fn move_guard(&mut self, guard: usize, dir: Direction) -> bool {
+        if let Some((pos, facing)) = self.guards[guard] {
+            if let Some(next) = self.map.step(pos, dir) {
+                self.guards[guard] = Some((next, facing));
+                return true;
             }
         }
+        false
     }
 
     /// Move guard direction
-    pub fn rotate_guard(&mut self, guard: usize, clockwise: bool) {
+    pub fn rotate_guard(&mut self, guard: usize, clockwise: bool) -> bool {
         if let Some((pos, dir)) = self.guards[guard] {
-            self.guards[guard] = if clockwise {
-                match dir {
-                    Direction::Up => Some((pos, Direction::Right)),
-                    Direction::Right => Some((pos, Direction::Down)),
-                    Direction::Down => Some((pos, Direction::Left)),
-                    Direction::Left => Some((pos, Direction::Up)),
-                }
-            } else {
-                match dir {
-                    Direction::Up => Some((pos, Direction::Left)),
-                    Direction::Right => Some((pos, Direction::Up)),
-                    Direction::Down => Some((pos, Direction::Right)),
-                    Direction::Left => Some((pos, Direction::Down)),
-                }
+            self.guards[guard] = Some((pos, dir.turn(self.rotation(clockwise))));
+            return true;
+        }
+        false
+    }
+
+    /// Open or close the door next to a point, returning whether there is
+    /// one; a door stays open while anyone stands in the doorway
+    pub fn toggle_door(&mut self, (x, y): Point, dir: Direction) -> bool {
+        if dir.diagonal() && !self.map.diagonal {
+            return false;
+        }
+        let (dx, dy) = dir.delta();
+        let (Ok(nx), Ok(ny)) = (u8::try_from(x as i16 + dx), u8::try_from(y as i16 + dy)) else {
+            return false;
+        };
+        let Some(Tile::Door { open }) = self.map.at(nx as usize, ny as usize) else {
+            return false;
+        };
+        let blocked = self
+            .positions
+            .iter()
+            .chain(self.guards.iter())
+            .flatten()
+            .any(|&(pos, _)| pos == (nx, ny));
+        if !(open && blocked) {
+            self.map
+                .set(nx as usize, ny as usize, Tile::Door { open: !open });
+        }
+        true
+    }
+
+    /// Eighths of a turn made by a single rotation, clockwise or not
+    fn rotation(&self, clockwise: bool) -> i8 {
+        let eighths = if self.config.diagonal { 1 } else { 2 };
+        if clockwise {
+            eighths
+        } else {
+            -eighths
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVEL: &str = "\
+#########
+#...'...#
+####.####
+#########
+---
+attacker 1 1 right
+target 7 1
+attacker 1 1 right ; the defender's, who won't need it
+target 7 1
+guard 4 2 up";
+
+    /// Game with an attacker heading for an open door, and a guard beside
+    /// it facing the other way
+    fn game() -> Game {
+        let config = Config {
+            viewcone_length: 3,
+            viewcone_width: 1,
+            players: 2,
+            num_guards: 1,
+            ..Config::default()
+        };
+        let level = LEVEL.parse().unwrap();
+        let rng = ChaCha8Rng::seed_from_u64(1);
+        let mut game = Game::from_level(String::new(), config, level, rng);
+        game.assign(1);
+        game.placed = true;
+        game
+    }
+
+    #[test]
+    fn door_closed_in_sight() {
+        let mut game = game();
+        let mut client = game.view(0);
+
+        // The guard shuts the door while the attacker watches
+        let turn = MsgToServer::Turn(vec![Action::Toggle(Direction::Up)]);
+        game.update(turn, 1).unwrap();
+        assert_eq!(game.map.at(4, 1), Some(Tile::Door { open: false }));
+        client.observe(&game.turn(0, 0));
+
+        // So walking into it is no surprise, and the client agrees
+        let mut turn = client.begin(0);
+        let mut actions = vec![];
+        for _ in 0..3 {
+            let action = Action::Move(Direction::Right);
+            if client.act(&mut turn, action).is_ok() {
+                actions.push(action);
             }
         }
+        assert_eq!(actions.len(), 2);
+        let bumped = [actions.clone(), vec![Action::Move(Direction::Right)]].concat();
+        assert!(game.clone().update(MsgToServer::Turn(bumped), 0).is_err());
+        game.update(MsgToServer::Turn(actions), 0).unwrap();
+        assert_eq!(game.positions[0], Some(((3, 1), Direction::Right)));
     }
 }
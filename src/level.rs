@@ -0,0 +1,425 @@
+use crate::game::{Map, ParseError, MAX_SIDE};
+use crate::{defaults, Config, Direction, Point, Result, Tile};
+use log::info;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::str::FromStr;
+
+/// Choice of map generation algorithm
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Generator {
+    /// The compiled-in test map
+    Fixed,
+    /// Rectangular rooms joined by corridors
+    Rooms,
+    /// Cellular automata caves
+    Caves,
+}
+
+/// A map together with the starting positions of everything on it
+pub struct Level {
+    pub map: Map,
+    pub positions: Vec<Option<(Point, Direction)>>,
+    pub targets: Vec<Option<Point>>,
+    pub guards: Vec<Option<(Point, Direction)>>,
+}
+
+impl Default for Level {
+    /// Currently use test defaults
+    fn default() -> Self {
+        Level {
+            map: defaults::MAP.parse().expect("built-in map is valid"),
+            positions: defaults::POSITIONS.to_vec(),
+            targets: defaults::TARGETS.to_vec(),
+            guards: defaults::GUARDS.to_vec(),
+        }
+    }
+}
+
+impl Level {
+    /// Build a level with the configured generator
+    pub fn new<R: Rng>(config: &Config, rng: &mut R) -> Result<Self> {
+        // Smallest map on which rooms and caves still make sense
+        let width = config.width.clamp(16, MAX_SIDE);
+        let height = config.height.clamp(16, MAX_SIDE);
+        let mut map = match config.generator {
+            Generator::Fixed => return Ok(Level::default().fit(config)?),
+            Generator::Rooms => rooms(width, height, rng),
+            Generator::Caves => caves(width, height, rng),
+        };
+        info!(
+            "Generated {:?} map of size {}x{}",
+            config.generator, width, height
+        );
+
+        // Fill in everything unreachable from the largest open area
+        let area = largest_area(&map);
+        let mut open = vec![false; map.buf.len()];
+        for &i in area.iter() {
+            open[i] = true;
+        }
+        for (tile, open) in map.buf.iter_mut().zip(open) {
+            if !open {
+                *tile = Tile::Wall;
+            }
+        }
+
+        let mut taken = vec![];
+        let mut positions = Vec::with_capacity(config.players);
+        let mut targets = Vec::with_capacity(config.players);
+        for _ in 0..config.players {
+            let start = pick(&area, &taken, rng)?;
+            taken.push(start);
+            positions.push(Some((point(&map, start), rng.gen())));
+
+            // Target somewhere in the far half of the reachable area
+            let dist = map.distances(&[point(&map, start)], &HashSet::new());
+            let dist = dist.tiles();
+            let max = dist.iter().flatten().max().copied().unwrap_or(0);
+            let far: Vec<usize> = area
+                .iter()
+                .copied()
+                .filter(|&i| dist[i].unwrap_or(0) * 2 >= max)
+                .collect();
+            let target = pick(&far, &taken, rng)?;
+            taken.push(target);
+            targets.push(Some(point(&map, target)));
+        }
+
+        let mut guards = Vec::with_capacity(config.num_guards);
+        for _ in 0..config.num_guards {
+            let guard = pick(&area, &taken, rng)?;
+            taken.push(guard);
+            guards.push(Some((point(&map, guard), rng.gen())));
+        }
+
+        Ok(Level {
+            map,
+            positions,
+            targets,
+            guards,
+        })
+    }
+}
+
+impl Level {
+    /// Load a level from a map file, checking it suits the configuration
+    pub fn load(path: &str, config: &Config) -> Result<Self> {
+        let text = read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        let level: Level = text.parse().map_err(|err| format!("{path}: {err}"))?;
+        info!("Loaded map from {}", path);
+        Ok(level.fit(config).map_err(|err| format!("{path}: {err}"))?)
+    }
+
+    /// Check the level has enough spawns and guards for the configuration,
+    /// dropping any to spare
+    fn fit(mut self, config: &Config) -> std::result::Result<Self, String> {
+        if self.positions.len() < config.players {
+            return Err(format!(
+                "map has {} attacker spawns but {} players are configured",
+                self.positions.len(),
+                config.players
+            ));
+        }
+        if self.guards.len() < config.num_guards {
+            return Err(format!(
+                "map has {} guards but {} are configured",
+                self.guards.len(),
+                config.num_guards
+            ));
+        }
+        self.positions.truncate(config.players);
+        self.targets.truncate(config.players);
+        self.guards.truncate(config.num_guards);
+        Ok(self)
+    }
+}
+
+impl FromStr for Level {
+    type Err = ParseError;
+
+    /// Parse a map grid, then a "---" line, then one entry per line of:
+    /// - `attacker X Y DIRECTION` spawn point of an attacker
+    /// - `target X Y` objective of the attacker with the same index
+    /// - `guard X Y DIRECTION` starting point of a guard
+    ///
+    /// Anything following a ';' is a comment.
+    fn from_str(text: &str) -> std::result::Result<Level, ParseError> {
+        let lines: Vec<&str> = text.lines().collect();
+        let split = lines
+            .iter()
+            .position(|l| l.trim() == "---")
+            .ok_or_else(|| {
+                ParseError::new(
+                    lines.len().max(1),
+                    1,
+                    "missing \"---\" line between map and metadata",
+                )
+            })?;
+        let map: Map = lines[..split].join("\n").parse()?;
+
+        let mut positions = vec![];
+        let mut targets = vec![];
+        let mut guards = vec![];
+        for (i, line) in lines.iter().enumerate().skip(split + 1) {
+            let line = line.split(';').next().unwrap_or_default();
+            let words = words(line);
+            let entry = Entry {
+                line: i + 1,
+                end: line.trim_end().len() + 1,
+                words: &words,
+                map: &map,
+            };
+
+            match words.first() {
+                None => continue,
+                Some((_, "attacker")) => {
+                    positions.push(Some((entry.point(1)?, entry.direction(3)?)));
+                    entry.finish(4)?;
+                }
+                Some((_, "target")) => {
+                    targets.push(Some(entry.point(1)?));
+                    entry.finish(3)?;
+                }
+                Some((_, "guard")) => {
+                    guards.push(Some((entry.point(1)?, entry.direction(3)?)));
+                    entry.finish(4)?;
+                }
+                Some((col, word)) => {
+                    return Err(ParseError::new(
+                        i + 1,
+                        *col,
+                        &format!("unknown entry \"{word}\""),
+                    ))
+                }
+            }
+        }
+
+        if positions.len() != targets.len() {
+            return Err(ParseError::new(
+                lines.len() + 1,
+                1,
+                &format!(
+                    "{} attackers but {} targets, expected one each",
+                    positions.len(),
+                    targets.len()
+                ),
+            ));
+        }
+
+        Ok(Level {
+            map,
+            positions,
+            targets,
+            guards,
+        })
+    }
+}
+
+/// A line of map metadata being parsed
+struct Entry<'a> {
+    line: usize,
+    end: usize,
+    words: &'a [(usize, &'a str)],
+    map: &'a Map,
+}
+
+impl<'a> Entry<'a> {
+    /// Word at the given index along with its column
+    fn word(&self, index: usize) -> std::result::Result<(usize, &'a str), ParseError> {
+        self.words
+            .get(index)
+            .copied()
+            .ok_or_else(|| ParseError::new(self.line, self.end, "entry is incomplete"))
+    }
+
+    /// Tile that can be walked on given by the two words from the given index
+    fn point(&self, index: usize) -> std::result::Result<Point, ParseError> {
+        let mut point = [0; 2];
+        for (i, p) in point.iter_mut().enumerate() {
+            let (col, word) = self.word(index + i)?;
+            *p = word.parse().map_err(|_| {
+                ParseError::new(self.line, col, &format!("\"{word}\" is not a coordinate"))
+            })?;
+        }
+
+        let col = self.word(index)?.0;
+        match self.map.at(point[0] as usize, point[1] as usize) {
+            Some(tile) if tile.walkable() => Ok((point[0], point[1])),
+            Some(_) => Err(ParseError::new(self.line, col, "point can't be walked on")),
+            None => Err(ParseError::new(self.line, col, "point is outside the map")),
+        }
+    }
+
+    /// Direction given by the word at the given index
+    fn direction(&self, index: usize) -> std::result::Result<Direction, ParseError> {
+        let (col, word) = self.word(index)?;
+        match word {
+            "up" => Ok(Direction::Up),
+            "down" => Ok(Direction::Down),
+            "left" => Ok(Direction::Left),
+            "right" => Ok(Direction::Right),
+            "up-left" => Ok(Direction::UpLeft),
+            "up-right" => Ok(Direction::UpRight),
+            "down-left" => Ok(Direction::DownLeft),
+            "down-right" => Ok(Direction::DownRight),
+            _ => Err(ParseError::new(
+                self.line,
+                col,
+                &format!("\"{word}\" is not a direction"),
+            )),
+        }
+    }
+
+    /// Check there's nothing left over
+    fn finish(&self, len: usize) -> std::result::Result<(), ParseError> {
+        if let Some((col, _)) = self.words.get(len) {
+            Err(ParseError::new(self.line, *col, "unexpected extra input"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Split a line into words along with their (1-based) columns
+fn words(line: &str) -> Vec<(usize, &str)> {
+    let mut words = vec![];
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        if c.is_whitespace() {
+            if let Some(s) = start.take() {
+                words.push((s + 1, &line[s..i]));
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        words.push((s + 1, &line[s..]));
+    }
+    words
+}
+
+/// Rooms joined by L-shaped corridors
+fn rooms<R: Rng>(width: usize, height: usize, rng: &mut R) -> Map {
+    let mut map = Map::new(width, height);
+    let max = (width.min(height) / 4).max(5);
+    let mut rooms: Vec<(usize, usize, usize, usize)> = vec![];
+
+    for _ in 0..(width * height / 32) {
+        let w = rng.gen_range(4..=max);
+        let h = rng.gen_range(4..=max);
+        let x = rng.gen_range(1..(width - w));
+        let y = rng.gen_range(1..(height - h));
+
+        // Keep a wall between neighbouring rooms
+        if rooms
+            .iter()
+            .any(|r| x <= r.0 + r.2 && r.0 <= x + w && y <= r.1 + r.3 && r.1 <= y + h)
+        {
+            continue;
+        }
+
+        for j in y..(y + h) {
+            for i in x..(x + w) {
+                map.set(i, j, Tile::Floor);
+            }
+        }
+
+        if let Some(&(px, py, pw, ph)) = rooms.last() {
+            let (x0, y0) = (px + pw / 2, py + ph / 2);
+            let (x1, y1) = (x + w / 2, y + h / 2);
+            let corner = if rng.gen() { (x1, y0) } else { (x0, y1) };
+            for (a, b) in [((x0, y0), corner), (corner, (x1, y1))] {
+                for j in a.1.min(b.1)..=a.1.max(b.1) {
+                    for i in a.0.min(b.0)..=a.0.max(b.0) {
+                        map.set(i, j, Tile::Floor);
+                    }
+                }
+            }
+        }
+        rooms.push((x, y, w, h));
+    }
+
+    map
+}
+
+/// Caves grown by cellular automata
+fn caves<R: Rng>(width: usize, height: usize, rng: &mut R) -> Map {
+    let mut map = Map::new(width, height);
+    for y in 1..(height - 1) {
+        for x in 1..(width - 1) {
+            if rng.gen_bool(0.55) {
+                map.set(x, y, Tile::Floor);
+            }
+        }
+    }
+
+    for _ in 0..5 {
+        let old = map.clone();
+        for y in 1..(height - 1) {
+            for x in 1..(width - 1) {
+                let walls = (y - 1..=y + 1)
+                    .flat_map(|j| (x - 1..=x + 1).map(move |i| (i, j)))
+                    .filter(|&(i, j)| old.at(i, j) == Some(Tile::Wall))
+                    .count();
+                let tile = if walls >= 5 { Tile::Wall } else { Tile::Floor };
+                map.set(x, y, tile);
+            }
+        }
+    }
+
+    map
+}
+
+/// Convert an index into the map buffer to a point
+fn point(map: &Map, index: usize) -> Point {
+    ((index % map.width) as u8, (index / map.width) as u8)
+}
+
+/// Pick a random index not already taken (or any if they're all taken)
+fn pick<R: Rng>(from: &[usize], taken: &[usize], rng: &mut R) -> Result<usize> {
+    if from.is_empty() {
+        return Err("generated map has no open floor to start on".into());
+    }
+    let free: Vec<usize> = from
+        .iter()
+        .copied()
+        .filter(|i| !taken.contains(i))
+        .collect();
+    Ok(if free.is_empty() {
+        from[rng.gen_range(0..from.len())]
+    } else {
+        free[rng.gen_range(0..free.len())]
+    })
+}
+
+/// Indices of the tiles in the largest connected area of floor
+fn largest_area(map: &Map) -> Vec<usize> {
+    let mut best: Vec<usize> = vec![];
+    let mut seen = vec![false; map.buf.len()];
+
+    for (i, &tile) in map.buf.iter().enumerate() {
+        if tile != Tile::Floor || seen[i] {
+            continue;
+        }
+        let area: Vec<usize> = map
+            .distances(&[point(map, i)], &HashSet::new())
+            .tiles()
+            .iter()
+            .enumerate()
+            .filter_map(|(j, d)| d.map(|_| j))
+            .collect();
+        for &j in area.iter() {
+            seen[j] = true;
+        }
+        if area.len() > best.len() {
+            best = area;
+        }
+    }
+
+    best
+}
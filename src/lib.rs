@@ -1,19 +1,57 @@
+mod ai;
 mod config;
 pub mod defaults;
+mod fov;
 mod game;
+mod level;
 mod net;
+mod noise;
+mod path;
+mod replay;
+mod save;
 mod ui;
 
+pub use ai::Difficulty;
 use clap::Parser;
 pub use config::Config;
-pub use game::{Direction, Game, Point, Status, Tile};
-pub use net::{Client, MsgToClient, MsgToServer, Server};
-pub use ui::{term::Terminal, Colour, Key, UIBackend, UserInterface};
+pub use game::{Action, Direction, Game, Outcome, Point, Profile, Status, Tile, Turn};
+pub use level::{Generator, Level};
+pub use net::{
+    Client, Lobby, LobbyAction, LobbyMsg, LobbyPlayer, MsgToClient, MsgToServer, Role, Server,
+};
+pub use replay::Replay;
+pub use ui::{headless::Headless, term::Terminal, Colour, Key, UIBackend, UserInterface};
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 #[derive(Parser)]
 pub struct Cli {
     /// IP address of server
+    #[clap(
+        required_unless_present = "replay",
+        default_value = "",
+        hide_default_value = true
+    )]
     pub address: String,
+    /// Session token to rejoin a game in progress (client only)
+    #[clap(long)]
+    pub token: Option<u64>,
+    /// Name to play under (client only)
+    #[clap(long)]
+    pub name: Option<String>,
+    /// Colour to play as (client only)
+    #[clap(long)]
+    pub colour: Option<Colour>,
+    /// Watch the game instead of playing (client only)
+    #[clap(long)]
+    pub spectate: bool,
+    /// Watch a recording of a game instead of connecting (client only)
+    #[clap(long)]
+    pub replay: Option<String>,
+    /// Resume a game saved to this file (server only)
+    #[clap(long)]
+    pub resume: Option<String>,
+    /// Seed for all randomness in the game, overriding hanzo.toml (server only)
+    #[clap(long)]
+    pub seed: Option<u64>,
 }
@@ -1,71 +1,288 @@
-use crate::{Direction, Game, Point, Result, Status, UIBackend, UserInterface};
-use bincode::{deserialize_from, serialize_into};
-use log::info;
+use crate::ai::{bot, Bot};
+use crate::replay::Recorder;
+use crate::save::Save;
+use crate::{
+    Action, Direction, Game, Outcome, Point, Profile, Result, Status, Tile, UIBackend,
+    UserInterface,
+};
+use bincode::{deserialize_from, serialize_into, DefaultOptions, Options};
+use log::{info, warn};
+use rand::{thread_rng, Rng};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::io::stdin;
+use std::io::ErrorKind;
 use std::net::{TcpListener, TcpStream};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread::{spawn, JoinHandle};
-use std::time::Duration;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Number of attempts to reconnect to the server
+const RETRIES: usize = 30;
+
+/// Number of lines of chat kept in the lobby
+const CHAT_LINES: usize = 10;
+
+/// Longest chat message in characters
+const MAX_CHAT: usize = 120;
+
+/// Longest player name in characters
+const MAX_NAME: usize = 20;
+
+/// Largest message in bytes the server reads from a client, well beyond
+/// anything genuine
+const MAX_MESSAGE: u64 = 1 << 16;
+
+/// Extra time the server waits for a turn, to allow for the connection
+const GRACE: Duration = Duration::from_secs(2);
+
+/// How often the server checks on each player when waiting on several
+const POLL: Duration = Duration::from_millis(10);
+
+/// A player's message, unless they ran out of time or disconnected
+type Reply = std::result::Result<MsgToServer, RecvTimeoutError>;
 
 #[derive(Serialize, Deserialize, Debug)]
 /// Information sent from server to client each turn
 pub struct MsgToClient {
     // Is it the player's turn?
     pub turn: bool,
+    // Whose turn is it?
+    pub current: usize,
     // Is the player the defender?
     pub defender: bool,
-    // Players' positions (if alive)
+    // Players' positions (if alive and visible)
     pub positions: Vec<Option<(Point, Direction)>>,
-    // Guards' positions (if alive)
+    // Guards' positions (if alive and visible)
     pub guards: Vec<Option<(Point, Direction)>>,
+    // Tiles the player has seen, as they last saw them (every door for
+    // spectators, who know the rest)
+    pub tiles: Vec<(Point, Tile)>,
+    // Noises the guards heard, and the way each came from (defender only)
+    pub heard: Vec<(usize, Direction)>,
+    // Number of attackers and guards still in play
+    pub remaining: (usize, usize),
+    // What became of each attacker no longer in play
+    pub outcomes: Vec<Option<Outcome>>,
+    // Time the current player has for their turn
+    pub time: Duration,
     // Game finished?
     pub quit: Status,
+    // Why the player's last message was refused
+    pub error: Option<String>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Information sent from client to server
+pub enum MsgToServer {
+    // Request made while waiting in the lobby
+    Lobby(LobbyAction),
+    // Starting positions of guards chosen by the defender
+    Place(Vec<Option<(Point, Direction)>>),
+    // Actions taken during the player's turn
+    Turn(Vec<Action>),
+}
+
+/// Version of the network protocol, to be bumped whenever any message
+/// (or anything they contain, such as `Game`) changes layout
+pub const PROTOCOL: u32 = 21;
+
+/// Optional features this build supports
+pub const CAPABILITIES: [&str; 6] = [
+    "actions",
+    "fog-of-war",
+    "reconnect",
+    "lobby",
+    "profiles",
+    "spectate",
+];
+
 #[derive(Serialize, Deserialize, Debug)]
-/// Information sent from client to server each turn
-pub struct MsgToServer {
-    // New position of player's character
-    pub new: Option<(Point, Direction)>,
-    // New positions of guards
-    pub guards: Vec<Option<(Point, Direction)>>,
-    // Game finished?
-    pub quit: Status,
+/// First message from client to server, whose layout must never change
+pub struct Hello {
+    // Version of the network protocol
+    pub protocol: u32,
+    // Version of the client's build
+    pub version: String,
+    // Optional features supported by the client
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    pub fn new() -> Self {
+        Hello {
+            protocol: PROTOCOL,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /// Reason for refusing to play with the other side, if any
+    fn check(&self, other: &Hello) -> Option<String> {
+        if self.protocol != other.protocol {
+            return Some(format!(
+                "incompatible versions, server is hanzo {} (protocol {}) but client is hanzo {} (protocol {})",
+                self.version, self.protocol, other.version, other.protocol
+            ));
+        }
+        let missing: Vec<&str> = self
+            .capabilities
+            .iter()
+            .filter(|c| !other.capabilities.contains(c))
+            .map(|c| c.as_str())
+            .collect();
+        if !missing.is_empty() {
+            return Some(format!(
+                "client (hanzo {}) is missing capabilities: {}",
+                other.version,
+                missing.join(", ")
+            ));
+        }
+        None
+    }
+
+    /// Check something written by another build, such as a replay, can be
+    /// read by this one
+    pub fn readable(&self, what: &str) -> Result<()> {
+        if self.protocol != PROTOCOL {
+            return Err(format!(
+                "{what} was written by hanzo {} (protocol {}), which is incompatible with protocol {}",
+                self.version, self.protocol, PROTOCOL
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+/// Server's reply to a client's hello, whose layout must never change
+pub enum Welcome {
+    // Server's own hello
+    Accepted(Hello),
+    // Reason the connection was refused
+    Refused(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Client's request for a player slot, sent after the handshake
+pub enum Join {
+    // Take a new slot, under the given profile
+    New(Profile),
+    // Take back the slot with this session token
+    Rejoin(u64),
+    // Watch the game without taking a slot
+    Spectate,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+/// Server's answer to a request for a player slot
+pub enum Joined {
+    // Session token for the slot, and whether it's being taken back
+    Accepted(u64, bool),
+    // Watching the game, which will be sent once it starts
+    Spectating,
+    // Reason the request was refused
+    Refused(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+/// Role a player would like to play
+pub enum Role {
+    Any,
+    Defender,
+    Attacker,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// A player waiting in the lobby
+pub struct LobbyPlayer {
+    pub profile: Profile,
+    pub role: Role,
+    pub ready: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// The lobby as seen by one of the players in it
+pub struct Lobby {
+    // Players waiting, the first of whom is the host
+    pub players: Vec<LobbyPlayer>,
+    // Number of players needed
+    pub size: usize,
+    // Index of the player receiving this
+    pub you: usize,
+    // Most recent chat messages
+    pub chat: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+/// Information sent from server to client before the game starts
+pub enum LobbyMsg {
+    // Latest state of the lobby
+    State(Lobby),
+    // Game is starting, with the initial game state to follow
+    Start,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Request made by a player in the lobby
+pub enum LobbyAction {
+    // Ask for a role
+    Role(Role),
+    // Mark as ready, or not
+    Ready(bool),
+    // Say something to everyone
+    Chat(String),
+    // Start the game (host only)
+    Start,
+}
+
+/// Anything sent from server to client once they've joined
+enum Outgoing {
+    Lobby(LobbyMsg),
+    Game(Box<Game>),
+    Turn(MsgToClient),
 }
 
 /// A client connection
 pub struct ClientThread {
     stream: TcpStream,
     tx: Sender<MsgToServer>,
-    rx: Receiver<MsgToClient>,
+    rx: Receiver<Outgoing>,
 }
 
 impl ClientThread {
-    pub fn run(&self, game: Game) -> Result<()> {
+    pub fn run(self) -> Result<()> {
         info!("Client connected!");
 
-        // Send initial game state
-        serialize_into(&self.stream, &game)?;
-
-        if game.player == game.defender {
-            // If this is the defender we wait to receive
-            // their choice of guard positions
-            let msg = deserialize_from(&self.stream)?;
-            self.tx.send(msg)?;
-        }
-
-        loop {
-            // Send latest info to client
-            let msg = self.rx.recv()?;
-            serialize_into(&self.stream, &msg)?;
-            if msg.quit != Status::Running {
-                break;
+        // Forward everything the client sends, until they disconnect
+        let stream = self.stream.try_clone()?;
+        let tx = self.tx;
+        spawn(move || {
+            while let Ok(msg) = receive(&stream) {
+                if tx.send(msg).is_err() {
+                    break;
+                }
             }
+        });
 
-            // Receive update if it's the player's turn
-            if msg.turn {
-                let msg = deserialize_from(&self.stream)?;
-                self.tx.send(msg)?;
+        // Send everything to the client until the game finishes
+        for msg in self.rx.iter() {
+            match msg {
+                Outgoing::Lobby(msg) => serialize_into(&self.stream, &msg)?,
+                Outgoing::Game(game) => serialize_into(&self.stream, &game)?,
+                Outgoing::Turn(msg) => {
+                    serialize_into(&self.stream, &msg)?;
+                    if msg.quit != Status::Running {
+                        break;
+                    }
+                }
             }
         }
 
@@ -75,16 +292,15 @@ impl ClientThread {
 
 /// A client handle
 pub struct ClientHandle {
-    pub handle: JoinHandle<()>,
-    pub tx: Sender<MsgToClient>,
+    handle: JoinHandle<()>,
+    tx: Sender<Outgoing>,
     pub rx: Receiver<MsgToServer>,
+    computer: bool,
 }
 
 impl ClientHandle {
-    pub fn new(listener: &TcpListener, game: Game) -> Result<Self> {
-        let stream = listener.accept()?.0;
-
-        let (tx0, rx0): (Sender<MsgToClient>, Receiver<MsgToClient>) = channel();
+    pub fn new(stream: TcpStream) -> Self {
+        let (tx0, rx0): (Sender<Outgoing>, Receiver<Outgoing>) = channel();
         let (tx1, rx1): (Sender<MsgToServer>, Receiver<MsgToServer>) = channel();
 
         let client = ClientThread {
@@ -92,101 +308,836 @@ impl ClientHandle {
             tx: tx1,
             rx: rx0,
         };
-        let handle = spawn(move || client.run(game).unwrap());
+        // A lost connection ends the reading thread, which the server
+        // sees as a hung up channel
+        let handle = spawn(move || {
+            if let Err(err) = client.run() {
+                warn!("Client disconnected: {}", err);
+            }
+        });
 
-        Ok(ClientHandle {
+        ClientHandle {
             handle,
             tx: tx0,
             rx: rx1,
-        })
+            computer: false,
+        }
+    }
+
+    /// A computer player, playing in its own thread without a connection,
+    /// which places the guards when first given the game if asked to
+    fn computer(mut bot: Box<dyn Bot>, place: bool) -> Self {
+        let (tx0, rx0): (Sender<Outgoing>, Receiver<Outgoing>) = channel();
+        let (tx1, rx1): (Sender<MsgToServer>, Receiver<MsgToServer>) = channel();
+
+        let handle = spawn(move || {
+            let mut game: Option<Game> = None;
+            for msg in rx0.iter() {
+                let reply = match msg {
+                    Outgoing::Lobby(_) => None,
+                    Outgoing::Game(view) => {
+                        let first = game.is_none();
+                        let game = game.insert(*view);
+                        (first && place && game.player == game.defender)
+                            .then(|| MsgToServer::Place(bot.place(game)))
+                    }
+                    Outgoing::Turn(msg) => {
+                        let Some(game) = &mut game else {
+                            continue;
+                        };
+                        game.observe(&msg);
+                        if msg.quit != Status::Running {
+                            break;
+                        }
+                        msg.turn.then(|| MsgToServer::Turn(bot.play(game)))
+                    }
+                };
+                if let Some(reply) = reply {
+                    if tx1.send(reply).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        ClientHandle {
+            handle,
+            tx: tx0,
+            rx: rx1,
+            computer: true,
+        }
+    }
+
+    /// Wait a while for the player's next message in game, skipping any
+    /// stale requests they made in the lobby
+    fn recv(&self, timeout: Duration) -> Reply {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.rx.recv_timeout(timeout)? {
+                MsgToServer::Lobby(_) => continue,
+                msg => return Ok(msg),
+            }
+        }
     }
 }
 
+/// Read a message from a client, giving up on any too big to be genuine
+/// rather than trusting the lengths it claims
+fn receive<T: DeserializeOwned>(stream: &TcpStream) -> bincode::Result<T> {
+    DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_MESSAGE)
+        .deserialize_from(stream)
+}
+
+/// Exchange hellos with a newly connected client and read their request
+fn handshake(stream: &TcpStream) -> Result<Join> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let hello: Hello = receive(stream)?;
+    let ours = Hello::new();
+    if let Some(reason) = ours.check(&hello) {
+        serialize_into(stream, &Welcome::Refused(reason.clone()))?;
+        return Err(reason.into());
+    }
+    serialize_into(stream, &Welcome::Accepted(ours))?;
+    let join = receive(stream)?;
+    stream.set_read_timeout(None)?;
+    Ok(join)
+}
+
+/// Accept connections and pass on requests for a player slot
+fn accept(listener: TcpListener, tx: Sender<(Join, TcpStream)>) {
+    while let Ok((stream, addr)) = listener.accept() {
+        match handshake(&stream) {
+            Ok(join) => {
+                if tx.send((join, stream)).is_err() {
+                    // Server has finished
+                    return;
+                }
+            }
+            Err(err) => warn!("Refused client at {}: {}", addr, err),
+        }
+    }
+}
+
+/// Refuse a request for a player slot
+fn refuse(stream: &TcpStream, reason: &str) {
+    warn!("Refused client: {}", reason);
+    serialize_into(stream, &Joined::Refused(reason.to_string())).ok();
+}
+
 /// A server to manage client connections
 pub struct Server {
-    clients: Vec<ClientHandle>,
+    clients: Vec<Option<ClientHandle>>,
+    tokens: Vec<u64>,
+    spectators: Vec<ClientHandle>,
+    recorder: Option<Recorder>,
+    joins: Receiver<(Join, TcpStream)>,
+    commands: Receiver<String>,
     game: Game,
+    current: usize,
+    turns: usize,
+    // Time left in each player's time bank
+    banks: Vec<Duration>,
+    resuming: bool,
+    // Why anyone's last message was refused
+    errors: Vec<(usize, String)>,
 }
 
 impl Server {
-    pub fn new(mut game: Game) -> Result<Self> {
+    /// Start listening for connections, and for commands on stdin
+    fn listen(game: Game) -> Result<Self> {
         info!(
             "Address: {}, players: {}",
             game.address, game.config.players
         );
 
-        let mut clients = Vec::with_capacity(game.config.players);
         let listener = TcpListener::bind(&game.address)?;
-        for i in 0..game.config.players {
-            let mut g = game.clone();
-            g.player = i;
-            clients.push(ClientHandle::new(&listener, g)?);
+        let (tx, joins) = channel();
+        spawn(move || accept(listener, tx));
+
+        let (tx, commands) = channel();
+        spawn(move || {
+            for line in stdin().lines().map_while(|line| line.ok()) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Server {
+            clients: vec![],
+            tokens: vec![],
+            spectators: vec![],
+            recorder: None,
+            joins,
+            commands,
+            game,
+            current: 0,
+            turns: 0,
+            banks: vec![],
+            resuming: false,
+            errors: vec![],
+        })
+    }
+
+    pub fn new(game: Game) -> Result<Self> {
+        let mut server = Server::listen(game)?;
+        let mut players = server.lobby()?;
+
+        // The computer takes any places left
+        let mut computers = vec![];
+        if server.game.config.computer_defender {
+            computers.push(("Computer".to_string(), Role::Defender));
+        }
+        for i in 0..server.game.config.bots {
+            computers.push((format!("Bot {}", i + 1), Role::Attacker));
+        }
+        for (name, role) in computers {
+            players.push(LobbyPlayer {
+                profile: Profile {
+                    name,
+                    colour: None,
+                    computer: true,
+                },
+                role,
+                ready: true,
+            });
+            server.tokens.push(thread_rng().gen());
+        }
+
+        let roles: Vec<Role> = players.iter().map(|p| p.role).collect();
+        let computer: Vec<bool> = players.iter().map(|p| p.profile.computer).collect();
+        server.game.profiles = players.into_iter().map(|p| p.profile).collect();
+        server.banks = vec![server.game.config.time_bank; roles.len()];
+
+        // Pick the defender from the computer, or those who want to be
+        let mut candidates: Vec<usize> = (0..roles.len())
+            .filter(|&i| computer[i] && roles[i] == Role::Defender)
+            .collect();
+        if candidates.is_empty() {
+            candidates = (0..roles.len())
+                .filter(|&i| roles[i] == Role::Defender)
+                .collect();
+        }
+        if candidates.is_empty() {
+            candidates = (0..roles.len())
+                .filter(|&i| roles[i] == Role::Any)
+                .collect();
+        }
+        if candidates.is_empty() {
+            candidates = (0..roles.len()).collect();
+        }
+        let defender = candidates[server.game.rng.gen_range(0..candidates.len())];
+        server.game.assign(defender);
+        info!("{} is the defender", server.game.name(defender));
+
+        // Now the computer knows which side it's on
+        for i in server.clients.len()..server.game.profiles.len() {
+            let bot = bot(&server.game, i);
+            server.clients.push(Some(ClientHandle::computer(bot, true)));
+        }
+
+        for (i, client) in server.clients.iter().enumerate() {
+            if let Some(client) = client {
+                client.tx.send(Outgoing::Lobby(LobbyMsg::Start)).ok();
+                client
+                    .tx
+                    .send(Outgoing::Game(Box::new(server.game.view(i))))
+                    .ok();
+            }
         }
 
         // Update guards' positions from defending player
-        let msg = clients[game.defender].rx.recv()?;
-        info!("Received guard positions from defender!");
-        game.update(msg, game.defender);
+        match server.wait(&[defender]).pop().and_then(|(_, reply)| reply) {
+            Some(Ok(msg)) => {
+                info!("Received guard positions from defender!");
+                if let Err(err) = server.game.update(msg, defender) {
+                    warn!("Guard positions refused: {}", err);
+                    server.errors.push((defender, err));
+                }
+            }
+            Some(Err(RecvTimeoutError::Timeout)) => {
+                warn!("Defender ran out of time placing guards");
+                let err = "you ran out of time".to_string();
+                server.errors.push((defender, err));
+            }
+            _ => warn!("Defender left before placing guards"),
+        }
+        // Whatever happened, the guards start where they are now
+        server.game.placed = true;
+
+        // Spectators who turned up early can now see the game
+        for spectator in server.spectators.iter() {
+            spectator
+                .tx
+                .send(Outgoing::Game(Box::new(server.game.clone())))
+                .ok();
+        }
 
-        Ok(Server { clients, game })
+        // Start recording now everything's in place
+        if let Some(path) = &server.game.config.replay {
+            match Recorder::new(path, &server.game) {
+                Ok(recorder) => {
+                    info!("Recording game to {}", path);
+                    server.recorder = Some(recorder);
+                }
+                Err(err) => warn!("Unable to record game to {}: {}", path, err),
+            }
+        }
+
+        Ok(server)
+    }
+
+    /// Resume a saved game, once all its players have rejoined
+    pub fn resume(path: &str, address: &str) -> Result<Self> {
+        let mut save = Save::read(path)?;
+        info!("Resuming game from {}", path);
+        save.game.address = address.to_string();
+
+        let mut server = Server::listen(save.game)?;
+        server.clients = save.tokens.iter().map(|_| None).collect();
+        server.tokens = save.tokens;
+        server.current = save.current;
+        server.banks = save.banks;
+        server.resuming = true;
+
+        // The computer comes straight back
+        for (i, profile) in server.game.profiles.iter().enumerate() {
+            if profile.computer {
+                let client = ClientHandle::computer(bot(&server.game, i), false);
+                client
+                    .tx
+                    .send(Outgoing::Game(Box::new(server.game.view(i))))
+                    .ok();
+                server.clients[i] = Some(client);
+            }
+        }
+
+        // Carry on recording from where the game was saved if possible
+        if let Some(path) = &server.game.config.replay {
+            let recorder = match save.recorded {
+                Some(len) => Recorder::resume(path, len),
+                None => Err("game wasn't being recorded".into()),
+            };
+            server.recorder = match recorder {
+                Ok(recorder) => Some(recorder),
+                Err(err) => {
+                    warn!("Unable to resume recording ({}), starting again", err);
+                    Recorder::new(path, &server.game)
+                        .map_err(|err| warn!("Unable to record game to {}: {}", path, err))
+                        .ok()
+                }
+            };
+        }
+        info!("Waiting for players to rejoin");
+
+        Ok(server)
+    }
+
+    /// Save the game so it can be resumed later
+    fn save(&self) {
+        let path = &self.game.config.save;
+        let save = Save {
+            game: self.game.clone(),
+            current: self.current,
+            tokens: self.tokens.clone(),
+            banks: self.banks.clone(),
+            recorded: self.recorder.as_ref().and_then(|r| r.len().ok()),
+        };
+        match save.write(path) {
+            Ok(()) => info!("Game saved to {}", path),
+            Err(err) => warn!("Unable to save game to {}: {}", path, err),
+        }
+    }
+
+    /// Gather players in the lobby until the host starts the game
+    fn lobby(&mut self) -> Result<Vec<LobbyPlayer>> {
+        // Leave room for the computer
+        let config = &self.game.config;
+        let size = config.players - config.computer_defender as usize - config.bots;
+        // Tokens must be unguessable, so don't come from the game's seed
+        let mut rng = thread_rng();
+        let mut players: Vec<LobbyPlayer> = vec![];
+        let mut chat: Vec<String> = vec![];
+        if self.game.config.computer_defender {
+            chat.push("* The computer will be the defender".to_string());
+        }
+        if self.game.config.bots > 0 {
+            chat.push(format!(
+                "* {} computer attackers will join",
+                self.game.config.bots
+            ));
+        }
+        let mut joined = 0;
+
+        loop {
+            let mut changed = false;
+
+            // Welcome new arrivals
+            while let Ok((join, stream)) = self.joins.try_recv() {
+                match join {
+                    Join::New(mut profile) if players.len() < size => {
+                        let token = rng.gen();
+                        if serialize_into(&stream, &Joined::Accepted(token, false)).is_ok() {
+                            joined += 1;
+                            profile.computer = false;
+                            profile.name = profile
+                                .name
+                                .chars()
+                                .filter(|c| !c.is_control())
+                                .take(MAX_NAME)
+                                .collect::<String>()
+                                .trim()
+                                .to_string();
+                            if profile.name.is_empty()
+                                || players.iter().any(|p| p.profile.name == profile.name)
+                            {
+                                profile.name = format!("Player {joined}");
+                            }
+                            chat.push(format!("* {} joined", profile.name));
+                            players.push(LobbyPlayer {
+                                profile,
+                                role: Role::Any,
+                                ready: false,
+                            });
+                            self.tokens.push(token);
+                            self.clients.push(Some(ClientHandle::new(stream)));
+                            changed = true;
+                        }
+                    }
+                    Join::New(_) => refuse(&stream, "lobby is full"),
+                    Join::Rejoin(_) => refuse(&stream, "game hasn't started yet"),
+                    Join::Spectate => self.spectate(stream, false),
+                }
+            }
+
+            // Handle requests from those waiting
+            let mut i = 0;
+            while i < players.len() {
+                let client = self.clients[i].as_ref().ok_or("missing lobby client")?;
+                match client.rx.try_recv() {
+                    Ok(MsgToServer::Lobby(action)) => {
+                        changed = true;
+                        match action {
+                            LobbyAction::Role(role) => players[i].role = role,
+                            LobbyAction::Ready(ready) => players[i].ready = ready,
+                            LobbyAction::Chat(text) => {
+                                let text: String = text
+                                    .chars()
+                                    .filter(|c| !c.is_control())
+                                    .take(MAX_CHAT)
+                                    .collect();
+                                if !text.is_empty() {
+                                    chat.push(format!("{}: {}", players[i].profile.name, text));
+                                }
+                            }
+                            LobbyAction::Start if i == 0 => {
+                                if players.len() < size {
+                                    chat.push(format!(
+                                        "* Waiting for {} more players",
+                                        size - players.len()
+                                    ));
+                                } else if players.iter().any(|p| !p.ready) {
+                                    chat.push("* Waiting for everyone to be ready".to_string());
+                                } else {
+                                    info!("Host started the game");
+                                    return Ok(players);
+                                }
+                            }
+                            LobbyAction::Start => (),
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(TryRecvError::Empty) => i += 1,
+                    Err(TryRecvError::Disconnected) => {
+                        let player = players.remove(i);
+                        self.clients.remove(i);
+                        self.tokens.remove(i);
+                        chat.push(format!("* {} left", player.profile.name));
+                        changed = true;
+                    }
+                }
+            }
+
+            // Tell everyone what's changed
+            if changed {
+                let start = chat.len().saturating_sub(CHAT_LINES);
+                chat.drain(..start);
+                for (you, client) in self.clients.iter().flatten().enumerate() {
+                    let lobby = Lobby {
+                        players: players.clone(),
+                        size,
+                        you,
+                        chat: chat.clone(),
+                    };
+                    client.tx.send(Outgoing::Lobby(LobbyMsg::State(lobby))).ok();
+                }
+            }
+
+            sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Let someone watch the game, sending them everything if it's begun
+    fn spectate(&mut self, stream: TcpStream, begun: bool) {
+        if !self.game.config.spectators {
+            return refuse(&stream, "spectating is turned off");
+        }
+        if serialize_into(&stream, &Joined::Spectating).is_ok() {
+            let spectator = ClientHandle::new(stream);
+            if begun {
+                spectator
+                    .tx
+                    .send(Outgoing::Game(Box::new(self.game.clone())))
+                    .ok();
+            }
+            self.spectators.push(spectator);
+            info!("Spectator joined");
+        }
+    }
+
+    /// Reconnect players who have rejoined, with a fresh snapshot, and
+    /// welcome new spectators
+    fn reconnect(&mut self) -> Result<()> {
+        // Nobody's left to play if only the computer's still connected
+        let gone = |c: &Option<ClientHandle>| c.as_ref().is_none_or(|c| c.computer);
+        if !self.resuming && self.clients.iter().all(gone) {
+            warn!("All players disconnected, waiting for someone to rejoin");
+        }
+
+        loop {
+            // Wait for everyone to come back to a resumed game, or for
+            // someone to come back if everyone has gone
+            let wait = if self.resuming {
+                self.clients.iter().any(|c| c.is_none())
+            } else {
+                self.clients.iter().all(gone)
+            };
+            let (join, stream) = if wait {
+                self.joins.recv()?
+            } else if let Ok(join) = self.joins.try_recv() {
+                join
+            } else {
+                self.resuming = false;
+                return Ok(());
+            };
+
+            match join {
+                Join::Rejoin(token) => match self.tokens.iter().position(|&t| t == token) {
+                    Some(player) => {
+                        if serialize_into(&stream, &Joined::Accepted(token, true)).is_ok() {
+                            let client = ClientHandle::new(stream);
+                            client
+                                .tx
+                                .send(Outgoing::Game(Box::new(self.game.view(player))))
+                                .ok();
+                            self.clients[player] = Some(client);
+                            info!("Player {} rejoined", player);
+                        }
+                    }
+                    None => refuse(&stream, "unknown session token"),
+                },
+                Join::New(_) => refuse(&stream, "game already in progress"),
+                Join::Spectate => self.spectate(stream, true),
+            }
+        }
+    }
+
+    /// Time a player has for their turn, including their time bank
+    fn allowed(&self, player: usize) -> Duration {
+        self.game.config.turn_time + self.banks[player]
+    }
+
+    /// Wait for each player's message for as long as they have, all at
+    /// once, charging any time over a turn's own to their time bank
+    fn wait(&mut self, players: &[usize]) -> Vec<(usize, Option<Reply>)> {
+        let start = Instant::now();
+        let (mut pending, gone): (Vec<usize>, Vec<usize>) = players
+            .iter()
+            .partition(|&&player| self.clients[player].is_some());
+        let mut replies: Vec<(usize, Option<Reply>)> =
+            gone.into_iter().map(|player| (player, None)).collect();
+
+        while !pending.is_empty() {
+            let mut still = vec![];
+            for &player in &pending {
+                let Some(client) = &self.clients[player] else {
+                    continue;
+                };
+                let deadline = self.allowed(player) + GRACE;
+                let left = deadline.saturating_sub(start.elapsed());
+                let timeout = if pending.len() > 1 {
+                    left.min(POLL)
+                } else {
+                    left
+                };
+                let reply = match client.recv(timeout) {
+                    Err(RecvTimeoutError::Timeout) if start.elapsed() < deadline => {
+                        still.push(player);
+                        continue;
+                    }
+                    reply => reply,
+                };
+                let over = start.elapsed().saturating_sub(self.game.config.turn_time);
+                self.banks[player] = self.banks[player].saturating_sub(over);
+                replies.push((player, Some(reply)));
+            }
+            pending = still;
+        }
+        replies
+    }
+
+    /// Add a turn's accepted messages to the recording, if there is one
+    fn record(&mut self, current: usize, msgs: &[(usize, MsgToServer)]) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.record(current, msgs) {
+                warn!("Stopped recording game: {}", err);
+                self.recorder = None;
+            }
+        }
     }
 
     pub fn run(&mut self) -> Result<()> {
-        let mut current: usize = 0;
         loop {
+            self.reconnect()?;
+            let current = self.current;
+            let movers = self.game.movers(current);
+
+            // Handle commands typed at the server
+            while let Ok(command) = self.commands.try_recv() {
+                match command.trim() {
+                    "save" => self.save(),
+                    "" => (),
+                    other => warn!("Unknown command: {}", other),
+                }
+            }
+
+            // Drop anything sent out of turn, like a reply that came too late
+            for client in movers.iter().filter_map(|&i| self.clients[i].as_ref()) {
+                while client.rx.try_recv().is_ok() {}
+            }
+
             // Check victory conditions
             self.game.victory();
 
             // Send updates to clients
+            let time = self.allowed(current);
             for (i, client) in self.clients.iter().enumerate() {
-                let msg = self.game.turn(i, current);
-                client.tx.send(msg)?;
+                let mut msg = self.game.turn(i, current);
+                msg.time = if msg.turn { self.allowed(i) } else { time };
+                msg.error = self
+                    .errors
+                    .iter()
+                    .find(|(player, _)| *player == i)
+                    .map(|(_, err)| err.clone());
+                if let Some(client) = client {
+                    // Ignore lost connections until it's their turn
+                    client.tx.send(Outgoing::Turn(msg)).ok();
+                }
             }
+            self.errors.clear();
+            self.spectators.retain(|spectator| {
+                let mut msg = self.game.spectate(current);
+                msg.time = time;
+                spectator.tx.send(Outgoing::Turn(msg)).is_ok()
+            });
             info!("Update broadcasted to clients");
 
             if self.game.quit != Status::Running {
                 break;
             }
 
-            // Receive update from current player's client
-            let msg = self.clients[current].rx.recv()?;
-            info!("Received update from player {}", current);
-            self.game.update(msg, current);
+            // Receive updates from the clients of everyone taking their turn
+            let mut msgs = vec![];
+            for (player, reply) in self.wait(&movers) {
+                match reply {
+                    Some(Ok(msg)) => {
+                        info!("Received update from player {}", player);
+                        msgs.push((player, msg));
+                    }
+                    Some(Err(RecvTimeoutError::Timeout)) => {
+                        warn!("Player {} ran out of time", player);
+                        self.errors
+                            .push((player, "you ran out of time".to_string()));
+                    }
+                    Some(Err(RecvTimeoutError::Disconnected)) => {
+                        warn!("Lost connection to player {}, skipping turn", player);
+                        self.clients[player] = None;
+                    }
+                    None => info!("Player {} is disconnected, skipping turn", player),
+                }
+            }
+            for (player, err) in self.game.update_all(msgs.clone()) {
+                warn!("Refused update from player {}: {}", player, err);
+                msgs.retain(|&(p, _)| p != player);
+                self.errors.push((player, err));
+            }
+            if !msgs.is_empty() {
+                self.record(current, &msgs);
+            }
+
+            self.current = self.game.next(current);
 
-            current = (current + 1) % self.game.config.players;
+            // Save every so often
+            self.turns += 1;
+            let autosave = self.game.config.autosave;
+            if autosave > 0 && self.turns.is_multiple_of(autosave) {
+                self.save();
+            }
         }
 
-        for client in self.clients.drain(0..) {
+        for client in self.clients.drain(0..).flatten() {
             client.handle.join().unwrap();
         }
+        for spectator in self.spectators.drain(0..) {
+            spectator.handle.join().unwrap();
+        }
 
         Ok(())
     }
 }
 
-/// A player client
+/// Connect to the server and make a request to join the game
+fn connect(address: &str, join: &Join) -> Result<(TcpStream, Joined)> {
+    let stream = TcpStream::connect(address)?;
+    serialize_into(&stream, &Hello::new())?;
+    match deserialize_from(&stream)? {
+        Welcome::Accepted(hello) => {
+            info!(
+                "Server is hanzo {} (protocol {})",
+                hello.version, hello.protocol
+            )
+        }
+        Welcome::Refused(reason) => return Err(format!("Server refused: {reason}").into()),
+    }
+
+    serialize_into(&stream, join)?;
+    match deserialize_from(&stream)? {
+        Joined::Refused(reason) => Err(format!("Server refused: {reason}").into()),
+        joined => Ok((stream, joined)),
+    }
+}
+
+/// Wait in the lobby until the host starts the game
+fn lobby<T: UIBackend>(stream: &TcpStream, ui: &mut UserInterface<T>) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+    loop {
+        match deserialize_from(stream) {
+            Ok(LobbyMsg::State(lobby)) => ui.lobby(lobby)?,
+            Ok(LobbyMsg::Start) => break,
+            Err(err) if !timed_out(&err) => return Err(err),
+            Err(_) => (),
+        }
+
+        if let Some(action) = ui.lobby_input()? {
+            serialize_into(stream, &MsgToServer::Lobby(action))?;
+        }
+    }
+    stream.set_read_timeout(None)?;
+    Ok(())
+}
+
+/// Did reading from the server just time out (rather than fail)?
+fn timed_out(err: &bincode::Error) -> bool {
+    match err.as_ref() {
+        bincode::ErrorKind::Io(err) => {
+            matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+        }
+        _ => false,
+    }
+}
+
+/// A player (or spectator) client
 pub struct Client<T: UIBackend> {
+    address: String,
+    rejoin: Join,
+    spectator: bool,
     stream: TcpStream,
     game: Game,
     ui: UserInterface<T>,
 }
 
 impl<T: UIBackend> Client<T> {
-    pub fn new(address: &str, mut ui: UserInterface<T>) -> Result<Self> {
-        let stream = TcpStream::connect(address)?;
+    pub fn new(
+        address: &str,
+        token: Option<u64>,
+        profile: Profile,
+        mut ui: UserInterface<T>,
+    ) -> Result<Self> {
+        let join = match token {
+            Some(token) => Join::Rejoin(token),
+            None => Join::New(profile),
+        };
+        let (stream, token, rejoined) = match connect(address, &join)? {
+            (stream, Joined::Accepted(token, rejoined)) => (stream, token, rejoined),
+            _ => return Err("Server sent an unexpected reply".into()),
+        };
+        info!(
+            "Connected to {} with session token {}. Waiting for server...",
+            address, token
+        );
+        if !rejoined {
+            lobby(&stream, &mut ui)?;
+        }
         let mut game: Game = deserialize_from(&stream)?;
-        info!("Connected to {}. Waiting for server...", address);
 
         // Defender sets positions of their guards
-        if game.player == game.defender {
+        if !rejoined && game.player == game.defender {
             let msg = game.place_guards(&mut ui)?;
             serialize_into(&stream, &msg)?;
         }
 
         // Display splash screen
-        ui.splash()?;
+        ui.splash(token)?;
+
+        Ok(Client {
+            address: address.to_string(),
+            rejoin: Join::Rejoin(token),
+            spectator: false,
+            stream,
+            game,
+            ui,
+        })
+    }
+
+    /// Watch the game without playing
+    pub fn spectate(address: &str, mut ui: UserInterface<T>) -> Result<Self> {
+        let stream = match connect(address, &Join::Spectate)? {
+            (stream, Joined::Spectating) => stream,
+            _ => return Err("Server sent an unexpected reply".into()),
+        };
+        info!("Spectating game at {}", address);
+        ui.message("Waiting for the game to start...")?;
+        let game: Game = deserialize_from(&stream)?;
 
-        Ok(Client { stream, game, ui })
+        Ok(Client {
+            address: address.to_string(),
+            rejoin: Join::Spectate,
+            spectator: true,
+            stream,
+            game,
+            ui,
+        })
+    }
+
+    /// Try to get back into the game after losing the connection
+    fn reconnect(&mut self) -> Result<()> {
+        warn!("Lost connection to server, reconnecting...");
+        for _ in 0..RETRIES {
+            self.ui.message("Connection lost! Reconnecting...")?;
+            sleep(Duration::from_secs(1));
+            if let Ok((stream, _)) = connect(&self.address, &self.rejoin) {
+                self.game = deserialize_from(&stream)?;
+                stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+                self.stream = stream;
+                info!("Reconnected to {}", self.address);
+                return Ok(());
+            }
+        }
+        Err("Lost connection to server".into())
     }
 
     pub fn run(&mut self) -> Result<()> {
@@ -196,32 +1147,195 @@ impl<T: UIBackend> Client<T> {
         let mut begun = false;
         loop {
             // Receive update from server
-            if let Ok(msg) = deserialize_from::<&TcpStream, MsgToClient>(&self.stream) {
-                self.game.display(&mut self.ui, &msg)?;
-                if msg.quit != Status::Running {
-                    quit = msg.quit;
-                    break;
+            match deserialize_from::<&TcpStream, MsgToClient>(&self.stream) {
+                Ok(msg) if self.spectator => {
+                    self.game.watch(&mut self.ui, &msg)?;
+                    if msg.quit != Status::Running {
+                        quit = msg.quit;
+                        break;
+                    }
                 }
-                begun = true;
+                Ok(msg) => {
+                    self.game.display(&mut self.ui, &msg)?;
+                    if msg.quit != Status::Running {
+                        quit = msg.quit;
+                        break;
+                    }
+                    begun = true;
 
-                // Send back update if it's our turn
-                if msg.turn {
-                    let msg = self.game.play(msg.defender, &mut self.ui)?;
-                    serialize_into(&self.stream, &msg)?;
+                    // Send back update if it's our turn
+                    if msg.turn {
+                        let msg = self.game.play(msg.defender, msg.time, &mut self.ui)?;
+                        if serialize_into(&self.stream, &msg).is_err() {
+                            self.reconnect()?;
+                        }
+                    }
+                }
+                Err(err) if !timed_out(&err) => self.reconnect()?,
+                Err(_) if self.spectator => {
+                    if self.ui.spectator_input(&self.game)? {
+                        self.ui.reset();
+                        return Ok(());
+                    }
+                }
+                Err(_) => {
+                    if self.ui.idle(begun, &mut self.game.rng)? {
+                        self.ui.reset();
+                        return Ok(());
+                    }
                 }
-            } else if self.ui.idle(begun)? {
-                self.ui.reset();
-                return Ok(());
             }
         }
         self.ui.reset();
 
-        if quit != Status::Quit {
-            println!("\n\nCongratulations! You win.\n");
+        let name = self.game.name(self.game.player);
+        if self.spectator {
+            match quit {
+                Status::AttackerVictory => println!("\n\nGame over! The attackers win."),
+                Status::DefenderVictory => println!("\n\nGame over! The defender wins."),
+                _ => println!("\n\nGame over! Thanks for watching."),
+            }
+        } else if quit != Status::Quit {
+            println!("\n\nCongratulations, {name}! You win.");
         } else {
-            println!("\n\nGame over! Thanks for playing.\n");
+            println!("\n\nGame over! Thanks for playing, {name}.");
         }
+        let attackers: Vec<String> = (0..self.game.profiles.len())
+            .filter(|&i| i != self.game.defender)
+            .map(|i| self.game.name(i))
+            .collect();
+        println!(
+            "Defender: {}. Attackers: {}.\n",
+            self.game.name(self.game.defender),
+            attackers.join(", ")
+        );
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Headless, Key, Level};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+    use std::io::Write;
+
+    const ADDRESS: &str = "127.0.0.1:47613";
+
+    const LEVEL: &str = "\
+##########
+#........#
+#.####...#
+#........#
+##########
+---
+attacker 1 3 right ; the defender's, who won't need it
+target 1 1
+attacker 1 1 right
+target 7 1
+guard 8 3 left";
+
+    /// Wait a while for something to show up on a client's screen
+    fn until(what: &str, done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "gave up waiting for {what}");
+            sleep(POLL);
+        }
+    }
+
+    /// Join the game with a client drawing to the given screen, playing
+    /// it out in its own thread
+    fn join(name: &str, screen: &Headless) -> JoinHandle<()> {
+        let profile = Profile {
+            name: name.to_string(),
+            ..Profile::default()
+        };
+        let screen = screen.clone();
+        spawn(move || {
+            // The server may not be listening yet
+            let mut client = (0..RETRIES)
+                .find_map(|_| {
+                    let ui = UserInterface::new(screen.clone());
+                    Client::new(ADDRESS, None, profile.clone(), ui)
+                        .map_err(|_| sleep(Duration::from_millis(100)))
+                        .ok()
+                })
+                .expect("couldn't join the server");
+            client.run().unwrap();
+        })
+    }
+
+    #[test]
+    fn scripted() {
+        let config = Config {
+            attacker_actions: 3,
+            defender_actions: 1,
+            viewcone_length: 6,
+            viewcone_width: 2,
+            players: 2,
+            num_guards: 1,
+            autosave: 0,
+            ..Config::default()
+        };
+        let level: Level = LEVEL.parse().unwrap();
+        let rng = ChaCha8Rng::seed_from_u64(1);
+        let game = Game::from_level(ADDRESS.to_string(), config, level, rng);
+        let server = spawn(move || {
+            let mut server = Server::new(game).unwrap();
+            server.run().unwrap();
+            server.game
+        });
+
+        // The host asks to defend, and starts once both are ready
+        let defender = Headless::new().unwrap();
+        let attacker = Headless::new().unwrap();
+        let host = join("Host", &defender);
+        until("the lobby", || defender.text().contains("Host"));
+        let guest = join("Guest", &attacker);
+        until("the guest", || defender.text().contains("Guest"));
+        defender.press([Key::Char('d'), Key::Char('r')]);
+        attacker.press([Key::Char('a'), Key::Char('r')]);
+        until("everyone to be ready", || {
+            let text = defender.text();
+            text.matches("ready").count() == 2 && !text.contains("not ready")
+        });
+        defender.press([Key::Char('s')]);
+
+        // The guard stays put while the attacker runs for their target
+        until("guard placement", || {
+            defender.last_message().contains("guards remaining")
+        });
+        defender.press([Key::Char(' ')]);
+        until("the defender's turn", || {
+            defender.last_message().starts_with("Your turn")
+        });
+        defender.press([Key::Char('.')]);
+        until("the attacker's turn", || {
+            attacker.last_message().starts_with("Your turn")
+        });
+        attacker.press([Key::Char('r'), Key::Right, Key::Right, Key::Right]);
+
+        for client in [host, guest] {
+            client.join().unwrap();
+        }
+        let game = server.join().unwrap();
+        assert_eq!(attacker.pending() + defender.pending(), 0);
+        assert_eq!(game.defender, 0);
+        assert_eq!(game.quit, Status::AttackerVictory);
+        assert_eq!(game.outcomes[1], Some(Outcome::Reached));
+    }
+
+    #[test]
+    fn oversized_hello() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        // The right protocol, then a version string claiming to be a terabyte
+        stream.write_all(&PROTOCOL.to_le_bytes()).unwrap();
+        stream.write_all(&(1u64 << 40).to_le_bytes()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        assert!(handshake(&accepted).is_err());
+    }
+}
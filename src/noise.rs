@@ -0,0 +1,50 @@
+use crate::game::Map;
+use crate::{Direction, Point};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+/// Loudness lost by a noise getting through a wall, door or window, rather
+/// than a tile of open ground
+const DAMPING: usize = 4;
+
+impl Map {
+    /// Tiles a noise made at a point can be heard on, with the way it comes
+    /// from on each, spreading a tile at a time round corners until it's
+    /// lost all its loudness
+    pub fn noise(&self, from: Point, loudness: usize) -> HashMap<Point, Direction> {
+        let index = |(x, y): Point| y as usize * self.width + x as usize;
+        let mut lost = vec![usize::MAX; self.buf.len()];
+        let mut heard = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        if lost.get(index(from)).is_none() {
+            return heard;
+        }
+        lost[index(from)] = 0;
+        queue.push(Reverse((0, from)));
+
+        while let Some(Reverse((d, pos))) = queue.pop() {
+            if d > lost[index(pos)] {
+                continue;
+            }
+            for dir in Direction::STRAIGHT {
+                let (dx, dy) = dir.delta();
+                let (Ok(x), Ok(y)) = (
+                    u8::try_from(pos.0 as i16 + dx),
+                    u8::try_from(pos.1 as i16 + dy),
+                ) else {
+                    continue;
+                };
+                let Some(tile) = self.at(x as usize, y as usize) else {
+                    continue;
+                };
+                let next = d + if tile.walkable() { 1 } else { DAMPING };
+                if next <= loudness && next < lost[index((x, y))] {
+                    lost[index((x, y))] = next;
+                    heard.insert((x, y), dir.turn(4));
+                    queue.push(Reverse((next, (x, y))));
+                }
+            }
+        }
+        heard
+    }
+}
@@ -0,0 +1,224 @@
+use crate::game::Map;
+use crate::{Direction, Point, Tile};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+
+/// Number of moves from every tile of a map to the nearest of some points
+pub struct DistanceMap {
+    width: usize,
+    dist: Vec<Option<usize>>,
+}
+
+impl DistanceMap {
+    /// Number of moves from a point, if it can be reached at all
+    pub fn get(&self, (x, y): Point) -> Option<usize> {
+        let (x, y) = (x as usize, y as usize);
+        if x < self.width {
+            self.dist.get(y * self.width + x).copied().flatten()
+        } else {
+            None
+        }
+    }
+
+    /// Distances indexed the same way as the map's tiles
+    pub fn tiles(&self) -> &[Option<usize>] {
+        &self.dist
+    }
+
+    /// Direction of a move that brings a point one move closer, if it isn't
+    /// already there
+    pub fn downhill(&self, map: &Map, pos: Point) -> Option<Direction> {
+        let here = self.get(pos)?;
+        map.moves().iter().copied().find(|&dir| {
+            map.through(pos, dir)
+                .and_then(|next| self.get(next))
+                .is_some_and(|d| d < here)
+        })
+    }
+}
+
+impl Map {
+    /// Whether a tile can be walked on
+    pub fn open(&self, (x, y): Point) -> bool {
+        self.at(x as usize, y as usize).is_some_and(Tile::walkable)
+    }
+
+    /// Whether a tile is a closed door
+    pub fn closed(&self, (x, y): Point) -> bool {
+        self.at(x as usize, y as usize) == Some(Tile::Door { open: false })
+    }
+
+    /// Directions moves can be made in
+    pub fn moves(&self) -> &'static [Direction] {
+        if self.diagonal {
+            &Direction::ALL
+        } else {
+            &Direction::STRAIGHT
+        }
+    }
+
+    /// Tile reached by moving one step from a point, if it can be walked on,
+    /// never squeezing diagonally between two walls
+    pub fn step(&self, pos: Point, dir: Direction) -> Option<Point> {
+        self.reach(pos, dir, |pos| self.open(pos))
+    }
+
+    /// Tile reached by moving one step from a point as if every door were
+    /// open, for planning routes that open them on the way
+    pub fn through(&self, pos: Point, dir: Direction) -> Option<Point> {
+        self.reach(pos, dir, |pos| self.open(pos) || self.closed(pos))
+    }
+
+    /// Tile one step from a point, if it's open and the step doesn't squeeze
+    /// diagonally between two tiles that aren't
+    pub fn reach(
+        &self,
+        (x, y): Point,
+        dir: Direction,
+        open: impl Fn(Point) -> bool,
+    ) -> Option<Point> {
+        if dir.diagonal() && !self.diagonal {
+            return None;
+        }
+        let (dx, dy) = dir.delta();
+        let nx = u8::try_from(x as i16 + dx).ok()?;
+        let ny = u8::try_from(y as i16 + dy).ok()?;
+        let squeeze = dir.diagonal() && !open((nx, y)) && !open((x, ny));
+        (open((nx, ny)) && !squeeze).then_some((nx, ny))
+    }
+
+    /// Tiles passed through following moves from a point, opening any doors
+    /// on the way and stopping short at any move that can't be made
+    pub fn follow(&self, from: Point, steps: &[Direction]) -> Vec<Point> {
+        let mut pos = from;
+        steps
+            .iter()
+            .map_while(|&dir| {
+                pos = self.through(pos, dir)?;
+                Some(pos)
+            })
+            .collect()
+    }
+
+    /// Shortest path between two points never setting foot on any of the
+    /// tiles to avoid, as the moves to make
+    pub fn path(&self, from: Point, to: Point, avoid: &HashSet<Point>) -> Option<Vec<Direction>> {
+        self.cheapest_path(from, to, |pos| (!avoid.contains(&pos)).then_some(1))
+    }
+
+    /// Cheapest path between two points as the moves to make, where each
+    /// move costs at least one for the tile it reaches, or can't be made
+    /// when the cost is None, and one more if there's a door to open first
+    pub fn cheapest_path(
+        &self,
+        from: Point,
+        to: Point,
+        cost: impl Fn(Point) -> Option<usize>,
+    ) -> Option<Vec<Direction>> {
+        let index = |(x, y): Point| y as usize * self.width + x as usize;
+        let estimate = |(x, y): Point| {
+            let (dx, dy) = (x.abs_diff(to.0) as usize, y.abs_diff(to.1) as usize);
+            if self.diagonal {
+                dx.max(dy)
+            } else {
+                dx + dy
+            }
+        };
+        let mut best = vec![usize::MAX; self.buf.len()];
+        let mut came: Vec<Option<(Point, Direction)>> = vec![None; self.buf.len()];
+        let mut queue = BinaryHeap::new();
+        best.get(index(from))?;
+        best[index(from)] = 0;
+        queue.push(Reverse((estimate(from), from)));
+
+        // A* with the distance ignoring walls as the estimate, which never
+        // overestimates as no move costs less than one
+        while let Some(Reverse((_, pos))) = queue.pop() {
+            if pos == to {
+                break;
+            }
+            for &dir in self.moves() {
+                let Some(next) = self.through(pos, dir) else {
+                    continue;
+                };
+                let Some(cost) = cost(next) else {
+                    continue;
+                };
+                let d = best[index(pos)] + cost.max(1) + self.closed(next) as usize;
+                if d < best[index(next)] {
+                    best[index(next)] = d;
+                    came[index(next)] = Some((pos, dir));
+                    queue.push(Reverse((d + estimate(next), next)));
+                }
+            }
+        }
+        if best.get(index(to)).is_none_or(|&d| d == usize::MAX) {
+            return None;
+        }
+
+        // Follow the way back from the destination
+        let mut steps = vec![];
+        let mut pos = to;
+        while let Some((prev, dir)) = came[index(pos)] {
+            steps.push(dir);
+            pos = prev;
+        }
+        steps.reverse();
+        Some(steps)
+    }
+
+    /// Number of moves from each tile to the nearest of the given points,
+    /// never setting foot on any of the tiles to avoid, going through doors
+    /// whether they're open or not
+    pub fn distances(&self, from: &[Point], avoid: &HashSet<Point>) -> DistanceMap {
+        self.spread(from, avoid, |pos, dir| self.through(pos, dir))
+    }
+
+    /// Number of steps from each tile to the nearest of the given points,
+    /// taking steps the given way
+    fn spread(
+        &self,
+        from: &[Point],
+        avoid: &HashSet<Point>,
+        step: impl Fn(Point, Direction) -> Option<Point>,
+    ) -> DistanceMap {
+        let mut dist = vec![None; self.buf.len()];
+        let mut queue = VecDeque::new();
+        for &(x, y) in from {
+            let i = y as usize * self.width + x as usize;
+            if (x as usize) < self.width && i < dist.len() && dist[i].is_none() {
+                dist[i] = Some(0);
+                queue.push_back((x, y));
+            }
+        }
+
+        while let Some(pos) = queue.pop_front() {
+            let d = dist[pos.1 as usize * self.width + pos.0 as usize].unwrap_or(0);
+            for &dir in self.moves() {
+                if let Some(next) = step(pos, dir) {
+                    let i = next.1 as usize * self.width + next.0 as usize;
+                    if dist[i].is_none() && !avoid.contains(&next) {
+                        dist[i] = Some(d + 1);
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+
+        DistanceMap {
+            width: self.width,
+            dist,
+        }
+    }
+
+    /// Number of moves from each tile to get out of the given area, such as
+    /// the tiles the guards can see, without stopping to open any doors
+    pub fn escape(&self, area: &HashSet<Point>) -> DistanceMap {
+        let outside: Vec<Point> = self
+            .tiles()
+            .filter(|(pos, tile)| tile.walkable() && !area.contains(pos))
+            .map(|(pos, _)| pos)
+            .collect();
+        self.spread(&outside, &HashSet::new(), |pos, dir| self.step(pos, dir))
+    }
+}
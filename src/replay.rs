@@ -0,0 +1,91 @@
+use crate::net::Hello;
+use crate::{Game, MsgToServer, Result, UIBackend, UserInterface};
+use bincode::{deserialize_from, serialize_into};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+
+/// Records a game to a file as it's played: a hello, the initial game,
+/// and then the messages the server accepted each turn
+pub struct Recorder {
+    file: BufWriter<File>,
+}
+
+impl Recorder {
+    /// Start recording from the game's initial state
+    pub fn new(path: &str, game: &Game) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        serialize_into(&mut file, &Hello::new())?;
+        serialize_into(&mut file, game)?;
+        file.flush()?;
+        Ok(Recorder { file })
+    }
+
+    /// Carry on recording a resumed game, dropping anything recorded after
+    /// it was saved, which is the given length of recording
+    pub fn resume(path: &str, len: u64) -> Result<Self> {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        if file.metadata()?.len() < len {
+            return Err("recording is shorter than when the game was saved".into());
+        }
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Recorder {
+            file: BufWriter::new(file),
+        })
+    }
+
+    /// Length of the recording so far
+    pub fn len(&self) -> Result<u64> {
+        Ok(self.file.get_ref().metadata()?.len())
+    }
+
+    /// Record whose turn it was and the messages from everyone who took
+    /// it, flushed so it survives a crash
+    pub fn record(&mut self, current: usize, msgs: &[(usize, MsgToServer)]) -> Result<()> {
+        serialize_into(&mut self.file, &(current, msgs))?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Whose turn it was, and the messages from everyone who took it
+type Turn = (usize, Vec<(usize, MsgToServer)>);
+
+/// A recorded game, ready to watch
+pub struct Replay {
+    // State of the game before each turn, and after the last
+    states: Vec<Game>,
+    // Whose turn each was
+    players: Vec<usize>,
+}
+
+impl Replay {
+    /// Load a recording, replaying every turn in it
+    pub fn load(path: &str) -> Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let hello: Hello = deserialize_from(&mut file)?;
+        hello.readable("replay")?;
+
+        let game: Game = deserialize_from(&mut file)?;
+        let mut states = vec![game];
+        let mut players = vec![];
+
+        // Read up to the end of the recording, which may have been cut short
+        while let Ok((current, msgs)) = deserialize_from::<_, Turn>(&mut file) {
+            let mut game = states[states.len() - 1].clone();
+            if let Some((_, err)) = game.update_all(msgs).pop() {
+                return Err(format!("turn {} of replay is invalid: {}", states.len(), err).into());
+            }
+            game.victory();
+            states.push(game);
+            players.push(current);
+        }
+
+        Ok(Replay { states, players })
+    }
+
+    /// Watch the recording
+    pub fn play<T: UIBackend>(&self, ui: &mut UserInterface<T>) -> Result<()> {
+        ui.replay(&self.states, &self.players)
+    }
+}
@@ -0,0 +1,43 @@
+use crate::net::Hello;
+use crate::{Game, Result};
+use bincode::{deserialize_from, serialize_into};
+use serde::{Deserialize, Serialize};
+use std::fs::{rename, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize)]
+/// Everything the server needs to resume a game
+pub struct Save {
+    pub game: Game,
+    // Whose turn it is
+    pub current: usize,
+    // Every player's session token
+    pub tokens: Vec<u64>,
+    // Time left in every player's time bank
+    pub banks: Vec<Duration>,
+    // Length of the game's recording when saved, if there is one
+    pub recorded: Option<u64>,
+}
+
+impl Save {
+    /// Write to a file, never leaving a half-written save behind
+    pub fn write(&self, path: &str) -> Result<()> {
+        let temp = format!("{path}.tmp");
+        let mut file = BufWriter::new(File::create(&temp)?);
+        serialize_into(&mut file, &Hello::new())?;
+        serialize_into(&mut file, self)?;
+        file.flush()?;
+        drop(file);
+        rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Read from a file written by a compatible build
+    pub fn read(path: &str) -> Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let hello: Hello = deserialize_from(&mut file)?;
+        hello.readable("save")?;
+        Ok(deserialize_from(&mut file)?)
+    }
+}
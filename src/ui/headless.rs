@@ -0,0 +1,218 @@
+use crate::{Colour, Key, Point, Result, UIBackend};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// A character on screen, with its colours
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    pub fg: Colour,
+    pub bg: Colour,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Cell {
+            ch: ' ',
+            fg: Colour::Reset,
+            bg: Colour::Reset,
+        }
+    }
+}
+
+struct State {
+    size: Point,
+    cells: Vec<Cell>,
+    message: String,
+    keys: VecDeque<Key>,
+}
+
+/// A user interface without a terminal, which draws to a screen in memory
+/// and takes its input from a queue of keys. Clones share the same screen
+/// and queue, so one can be kept to script and inspect the other.
+#[derive(Clone)]
+pub struct Headless {
+    state: Arc<Mutex<State>>,
+}
+
+impl Headless {
+    /// New screen of the given size, with no keys queued
+    pub fn with_size(size: Point) -> Self {
+        let state = State {
+            size,
+            cells: vec![Cell::default(); size.0 as usize * size.1 as usize],
+            message: String::new(),
+            keys: VecDeque::new(),
+        };
+        Headless {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // Nothing can be left half-done by a panic while locked
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Queue up keys to be pressed
+    pub fn press<I: IntoIterator<Item = Key>>(&self, keys: I) {
+        self.state().keys.extend(keys);
+    }
+
+    /// Number of keys still waiting to be pressed
+    pub fn pending(&self) -> usize {
+        self.state().keys.len()
+    }
+
+    /// Character and colours at a point on screen
+    pub fn cell(&self, pos: Point) -> Option<Cell> {
+        let state = self.state();
+        if pos.0 < state.size.0 && pos.1 < state.size.1 {
+            Some(state.cells[pos.1 as usize * state.size.0 as usize + pos.0 as usize])
+        } else {
+            None
+        }
+    }
+
+    /// Characters on screen, one line per row without trailing spaces,
+    /// leaving out the message line
+    pub fn text(&self) -> String {
+        let state = self.state();
+        let width = state.size.0 as usize;
+        let rows = (state.size.1 as usize).saturating_sub(1);
+        let mut lines: Vec<String> = (0..rows)
+            .map(|y| {
+                let row = &state.cells[y * width..(y + 1) * width];
+                let line: String = row.iter().map(|c| c.ch).collect();
+                line.trim_end().to_string()
+            })
+            .collect();
+        while lines.last().is_some_and(|l| l.is_empty()) {
+            lines.pop();
+        }
+        lines.join("\n")
+    }
+
+    /// Latest message printed to the bottom of the screen
+    pub fn last_message(&self) -> String {
+        self.state().message.clone()
+    }
+}
+
+impl UIBackend for Headless {
+    /// New screen the size of a standard terminal
+    fn new() -> Result<Self> {
+        Ok(Headless::with_size((80, 24)))
+    }
+
+    /// Draw to the screen, cutting off anything beyond its edge
+    fn draw(&mut self, pos: Point, str: &str, fg: Colour, bg: Colour) -> Result<()> {
+        let mut state = self.state();
+        let (width, height) = (state.size.0 as usize, state.size.1 as usize);
+        let (x, y) = (pos.0 as usize, pos.1 as usize);
+        if y >= height {
+            return Ok(());
+        }
+        for (i, ch) in str.chars().enumerate().take(width.saturating_sub(x)) {
+            state.cells[y * width + x + i] = Cell { ch, fg, bg };
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.state().cells.fill(Cell::default());
+        Ok(())
+    }
+
+    /// Press the next key in the queue, or wait like a real user who
+    /// isn't pressing anything
+    fn input(&mut self, timeout: Duration) -> Result<Option<Key>> {
+        let key = self.state().keys.pop_front();
+        if key.is_none() {
+            sleep(timeout);
+        }
+        Ok(key)
+    }
+
+    fn size(&self) -> Point {
+        self.state().size
+    }
+
+    fn message(&mut self, msg: &str) -> Result<()> {
+        self.state().message = msg.to_string();
+        Ok(())
+    }
+
+    fn reset(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Game, UserInterface};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    const LEVEL: &str = "\
+##########
+#........#
+#.####...#
+#........#
+##########
+---
+attacker 1 1 right
+target 7 1
+attacker 1 3 right ; the defender's, who won't need it
+target 1 1
+guard 8 3 left";
+
+    /// Game on a small map, with one attacker and a guard at the far end
+    fn game() -> Game {
+        let config = Config {
+            attacker_actions: 2,
+            defender_actions: 1,
+            viewcone_length: 6,
+            viewcone_width: 2,
+            players: 2,
+            num_guards: 1,
+            ..Config::default()
+        };
+        let level = LEVEL.parse().unwrap();
+        let rng = ChaCha8Rng::seed_from_u64(1);
+        let mut game = Game::from_level(String::new(), config, level, rng);
+        game.assign(1);
+        game
+    }
+
+    #[test]
+    fn views() {
+        let game = game();
+
+        let attacker = Headless::with_size((22, 11));
+        UserInterface::new(attacker.clone())
+            .display_attacker(&game.view(0))
+            .unwrap();
+        let seen = "\n\n\n\n              ###\n           A.....X\n              ##";
+        assert_eq!(attacker.text(), seen);
+        assert_eq!(attacker.cell((11, 5)).unwrap().fg, Colour::Cyan);
+
+        let defender = Headless::with_size((22, 11));
+        UserInterface::new(defender.clone())
+            .display_defender(&game.view(1), true)
+            .unwrap();
+        let seen = "\n\n   ##########\n   #........#\n   #.####...#\n   #.......G#\n   ##########";
+        assert_eq!(defender.text(), seen);
+        assert_eq!(defender.cell((11, 5)).unwrap().fg, Colour::Red);
+        assert_eq!(defender.cell((10, 5)).unwrap().bg, Colour::Red);
+
+        // Neither can see the other, and only the attacker knows their target
+        assert!(!attacker.text().contains('G'));
+        assert!(!defender.text().contains('A') && !defender.text().contains('X'));
+    }
+}
@@ -1,19 +1,31 @@
+pub mod headless;
 pub mod term;
 
-use crate::{Direction, Game, Point, Result, Status, Tile};
-use rand::{thread_rng, Rng};
+use crate::{Action, Direction, Game, Lobby, LobbyAction, Point, Result, Role, Tile};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 use std::time::{Duration, Instant};
 
+#[derive(Clone, Copy)]
 pub enum Key {
     Tab,
+    Enter,
+    Backspace,
+    Esc,
     Left,
     Down,
     Up,
     Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
     Char(char),
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Colour {
     Black,
     Red,
@@ -27,6 +39,25 @@ pub enum Colour {
     Reset,
 }
 
+impl FromStr for Colour {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Colour, String> {
+        match s.to_lowercase().as_str() {
+            "black" => Ok(Colour::Black),
+            "red" => Ok(Colour::Red),
+            "green" => Ok(Colour::Green),
+            "yellow" => Ok(Colour::Yellow),
+            "blue" => Ok(Colour::Blue),
+            "magenta" => Ok(Colour::Magenta),
+            "cyan" => Ok(Colour::Cyan),
+            "white" => Ok(Colour::White),
+            "grey" | "gray" => Ok(Colour::Grey),
+            _ => Err(format!("unknown colour \"{s}\"")),
+        }
+    }
+}
+
 pub trait UIBackend {
     /// Initialise the user interface
     fn new() -> Result<Self>
@@ -55,108 +86,115 @@ pub trait UIBackend {
     fn reset(&mut self);
 }
 
+/// Time between turns when playing a replay
+const REPLAY_SPEED: Duration = Duration::from_secs(1);
+
+/// Time left in a turn when the player is told to hurry up
+const WARNING: Duration = Duration::from_secs(10);
+
 pub struct UserInterface<T: UIBackend> {
     backend: T,
     centre: Option<(Point, Direction)>,
+    map: (usize, usize),
     seen: HashMap<Point, Tile>,
     guard: usize,
+    notice: Option<String>,
+    waiting: Option<String>,
+    lobby: Option<Lobby>,
+    typing: Option<String>,
+    perspective: Option<usize>,
+    running: bool,
 }
 
 impl<T: UIBackend> UserInterface<T> {
     pub fn new(backend: T) -> Self {
         let centre = None;
+        let map = (0, 0);
         let guard = 0;
         let seen = HashMap::new();
 
         UserInterface {
             backend,
             centre,
+            map,
             seen,
             guard,
+            notice: None,
+            waiting: None,
+            lobby: None,
+            typing: None,
+            perspective: None,
+            running: false,
         }
     }
 
-    fn defender(&mut self, game: &mut Game, key: Key, set: &mut bool) -> isize {
-        match key {
-            Key::Tab => loop {
-                self.guard = (self.guard + 1) % game.guards.len();
-                if game.guards[self.guard].is_some() {
-                    return 0;
-                }
-            },
-            Key::Left => game.move_guard(self.guard, -1, 0),
-            Key::Right => game.move_guard(self.guard, 1, 0),
-            Key::Up => game.move_guard(self.guard, 0, -1),
-            Key::Down => game.move_guard(self.guard, 0, 1),
-            Key::Char(c) => match c {
-                'q' => {
-                    game.quit = Status::Quit;
-                    return game.config.defender_actions;
-                }
-                '[' => game.rotate_guard(self.guard, false),
-                ']' => game.rotate_guard(self.guard, true),
-                '.' => (),
-                ' ' => {
-                    *set = true;
-                }
-                _ => return 0,
-            },
-        }
-        1
-    }
-
-    fn player(&self, game: &mut Game, key: Key) -> isize {
+    /// Map from keys to player actions
+    fn action(&self, key: Key, game: &Game, defender: bool) -> Option<Action> {
+        let step = |dir| {
+            Some(if self.running && !defender {
+                Action::Run(dir)
+            } else {
+                Action::Move(dir)
+            })
+        };
         match key {
-            Key::Tab => (),
-            Key::Left => game.move_player(-1, 0),
-            Key::Right => game.move_player(1, 0),
-            Key::Up => game.move_player(0, -1),
-            Key::Down => game.move_player(0, 1),
+            Key::Tab if defender => Some(Action::Cycle),
+            Key::Left => step(Direction::Left),
+            Key::Right => step(Direction::Right),
+            Key::Up => step(Direction::Up),
+            Key::Down => step(Direction::Down),
+            Key::Home => step(Direction::UpLeft),
+            Key::PageUp => step(Direction::UpRight),
+            Key::End => step(Direction::DownLeft),
+            Key::PageDown => step(Direction::DownRight),
             Key::Char(c) => match c {
-                'q' => {
-                    game.quit = Status::Quit;
-                    return game.config.attacker_actions;
+                'q' => Some(Action::Quit),
+                '.' => Some(Action::Wait),
+                '[' => Some(Action::Rotate(false)),
+                ']' => Some(Action::Rotate(true)),
+                'o' => {
+                    // The door the player (or selected guard) is facing
+                    let agent = if defender {
+                        game.guards.get(self.guard).copied().flatten()
+                    } else {
+                        game.positions[game.player]
+                    };
+                    agent.map(|(_, dir)| Action::Toggle(dir))
                 }
-                '.' => (),
-                '[' => game.rotate_player(false),
-                ']' => game.rotate_player(true),
-                _ => return 0,
+                _ => None,
             },
+            _ => None,
         }
-        1
     }
 
     /// Display game status
     fn status(&mut self, game: &Game, ap: isize, rem: Duration) -> Result<()> {
+        let hurry = if rem < WARNING { " Hurry up!" } else { "" };
+        let running = if self.running { ", Running" } else { "" };
         self.backend.message(&format!(
-            "Your turn! Attackers: {}, Guards: {}, Actions: {}, Turn Time: {}s",
-            game.positions.iter().filter(|&x| x.is_some()).count(),
-            game.guards.iter().filter(|&x| x.is_some()).count(),
+            "Your turn, {}! Attackers: {}, Guards: {}, Actions: {}{running}, Turn Time: {}s{hurry}",
+            game.name(game.player),
+            game.remaining.0,
+            game.remaining.1,
             ap,
             rem.as_secs(),
         ))?;
         Ok(())
     }
 
-    /// Centre view of map on desired point
+    /// Centre view of map on desired point (or the middle of the map)
     fn map_to_display(&self, pos: Point) -> Option<Point> {
         let size = self.backend.size();
-        let new = if let Some((centre, _)) = self.centre {
-            let dx = (size.0 / 2) as isize - centre.0 as isize;
-            let dy = ((size.1 - 1) / 2) as isize - centre.1 as isize;
-            let x = pos.0 as isize + dx;
-            let y = pos.1 as isize + dy;
-            if x >= 0 && y >= 0 {
-                (x as u8, y as u8)
-            } else {
-                return None;
-            }
+        let (cx, cy) = if let Some((centre, _)) = self.centre {
+            (centre.0 as isize, centre.1 as isize)
         } else {
-            (pos.0, pos.1)
+            ((self.map.0 / 2) as isize, (self.map.1 / 2) as isize)
         };
+        let x = pos.0 as isize + (size.0 / 2) as isize - cx;
+        let y = pos.1 as isize + ((size.1 - 1) / 2) as isize - cy;
 
-        if new.0 < size.0 && new.1 < size.1 - 1 {
-            Some(new)
+        if x >= 0 && y >= 0 && x < size.0 as isize && y < size.1 as isize - 1 {
+            Some((x as u8, y as u8))
         } else {
             None
         }
@@ -166,6 +204,16 @@ impl<T: UIBackend> UserInterface<T> {
         self.backend.message(str)
     }
 
+    /// Name the player whose turn everyone is waiting on
+    pub fn wait_for(&mut self, name: &str) {
+        self.waiting = Some(name.to_string());
+    }
+
+    /// Leave a notice for the player to see while waiting
+    pub fn notify(&mut self, str: &str) {
+        self.notice = Some(str.to_string());
+    }
+
     /// Display current game state on terminal
     pub fn display(&mut self, game: &Game, defender: bool) -> Result<()> {
         if defender {
@@ -180,6 +228,7 @@ impl<T: UIBackend> UserInterface<T> {
         self.backend.clear()?;
 
         self.centre = game.guards[self.guard];
+        self.map = (game.map.width, game.map.height);
 
         if full {
             // Display map
@@ -216,9 +265,26 @@ impl<T: UIBackend> UserInterface<T> {
             }
 
             // Display visible players
-            for (player, _) in game.positions.iter().flatten().filter(|&p| p.0 == *pos) {
-                if let Some(p) = self.map_to_display(*player) {
-                    self.backend.draw(p, "A", Colour::Blue, Colour::White)?;
+            for (i, player) in game.positions.iter().enumerate() {
+                if let Some((player, _)) = player.filter(|p| p.0 == *pos) {
+                    if let Some(p) = self.map_to_display(player) {
+                        let fg = game.colour(i).unwrap_or(Colour::Blue);
+                        self.backend.draw(p, "A", fg, Colour::White)?;
+                    }
+                }
+            }
+        }
+
+        // Mark the way each noise the guards heard came from
+        for &(guard, dir) in game.heard.iter() {
+            if let Some(((x, y), _)) = game.guards.get(guard).copied().flatten() {
+                let (dx, dy) = dir.delta();
+                let (Ok(x), Ok(y)) = (u8::try_from(x as i16 + dx), u8::try_from(y as i16 + dy))
+                else {
+                    continue;
+                };
+                if let Some(p) = self.map_to_display((x, y)) {
+                    self.backend.draw(p, "!", Colour::Yellow, Colour::Reset)?;
                 }
             }
         }
@@ -247,6 +313,7 @@ impl<T: UIBackend> UserInterface<T> {
         self.backend.clear()?;
 
         self.centre = game.positions[game.player];
+        self.map = (game.map.width, game.map.height);
 
         // Display previously seen parts of map
         for (pos, tile) in self.seen.iter() {
@@ -256,12 +323,10 @@ impl<T: UIBackend> UserInterface<T> {
             }
         }
 
-        // Determine all positions visible to defender
+        // Determine all positions the player would be seen on by the defender
         let mut defender = HashSet::new();
         for &guard in game.guards.iter() {
-            for (pos, _) in game.view_cone(guard).iter() {
-                defender.insert(*pos);
-            }
+            defender.extend(game.spots(guard));
         }
 
         // Determine all positions visible to player
@@ -300,15 +365,12 @@ impl<T: UIBackend> UserInterface<T> {
             }
 
             // Display visible other players
-            for player in game
-                .positions
-                .iter()
-                .flatten()
-                .map(|x| x.0)
-                .filter(|&p| p == *pos)
-            {
-                if let Some(p) = self.map_to_display(player) {
-                    self.backend.draw(p, "A", Colour::Yellow, Colour::Reset)?;
+            for (i, player) in game.positions.iter().enumerate() {
+                if let Some((player, _)) = player.filter(|p| p.0 == *pos) {
+                    if let Some(p) = self.map_to_display(player) {
+                        let fg = game.colour(i).unwrap_or(Colour::Yellow);
+                        self.backend.draw(p, "A", fg, Colour::Reset)?;
+                    }
                 }
             }
 
@@ -323,7 +385,8 @@ impl<T: UIBackend> UserInterface<T> {
         // Finally display player
         if let Some((pos, _)) = game.positions[game.player] {
             if let Some(p) = self.map_to_display(pos) {
-                self.backend.draw(p, "A", Colour::Cyan, Colour::Reset)?;
+                let fg = game.colour(game.player).unwrap_or(Colour::Cyan);
+                self.backend.draw(p, "A", fg, Colour::Reset)?;
             }
         }
 
@@ -331,100 +394,247 @@ impl<T: UIBackend> UserInterface<T> {
         Ok(())
     }
 
-    /// Event loop to get user input
-    pub fn input(&mut self, game: &mut Game, defender: bool) -> Result<()> {
-        let timer = Instant::now();
-        self.guard = game.guards.iter().position(|&x| x.is_some()).unwrap_or(0);
+    /// Display current game state on terminal for someone watching, either
+    /// everything or just what the chosen player can see
+    fn display_view(&mut self, game: &Game) -> Result<()> {
+        match self.perspective {
+            Some(player) if player == game.defender => {
+                self.display_defender(&game.view(player), true)
+            }
+            Some(player) => self.display_attacker(&game.view(player)),
+            None => {
+                self.display_defender(game, true)?;
+
+                // Display every target and player too
+                for pos in game.targets.iter().flatten() {
+                    if let Some(p) = self.map_to_display(*pos) {
+                        self.backend.draw(p, "X", Colour::Green, Colour::Reset)?;
+                    }
+                }
+                for (i, player) in game.positions.iter().enumerate() {
+                    if let Some((pos, _)) = player {
+                        if let Some(p) = self.map_to_display(*pos) {
+                            let fg = game.colour(i).unwrap_or(Colour::Cyan);
+                            self.backend.draw(p, "A", fg, Colour::Reset)?;
+                        }
+                    }
+                }
+                self.backend.flush()
+            }
+        }
+    }
 
-        let mut detected: isize = 3;
-        let mut actions: isize = if defender {
-            game.config.defender_actions
-        } else {
-            game.config.attacker_actions
+    /// Describe whose view is being watched
+    fn view_name(&self, game: &Game) -> String {
+        match self.perspective {
+            Some(player) => format!("{}'s view", game.name(player)),
+            None => "everything".to_string(),
+        }
+    }
+
+    /// Cycle through each player's view, then back to everything
+    fn switch_view(&mut self, game: &Game) {
+        self.perspective = match self.perspective {
+            None if !game.positions.is_empty() => Some(0),
+            Some(player) if player + 1 < game.positions.len() => Some(player + 1),
+            _ => None,
         };
-        while actions > 0 {
-            if let Some(remaining) = game.config.turn_time.checked_sub(timer.elapsed()) {
-                self.status(game, actions, remaining)?;
-            } else {
-                break;
-            }
+        self.seen.clear();
+    }
 
-            if !defender && game.positions[game.player].is_none() {
-                break;
+    /// Display current game state on terminal for a spectator
+    pub fn spectate(&mut self, game: &Game) -> Result<()> {
+        self.display_view(game)?;
+        let waiting = match &self.waiting {
+            Some(name) => format!(" Waiting for {name}."),
+            None => String::new(),
+        };
+        let notice = match &self.notice {
+            Some(notice) => format!("{notice} "),
+            None => String::new(),
+        };
+        self.backend.message(&format!(
+            "{notice}Spectating: {}.{waiting} [TAB] switch view, [q] quit",
+            self.view_name(game)
+        ))
+    }
+
+    /// Event loop for a spectator, returning whether they've quit
+    pub fn spectator_input(&mut self, game: &Game) -> Result<bool> {
+        match self.backend.input(Duration::from_millis(100))? {
+            Some(Key::Char('q')) => return Ok(true),
+            Some(Key::Tab) => {
+                self.switch_view(game);
+                self.spectate(game)?;
             }
+            _ => (),
+        }
+        Ok(false)
+    }
 
-            if let Some(k) = self
-                .backend
-                .input(Duration::from_millis(game.config.input_timeout))?
-            {
-                actions -= if defender {
-                    let mut x = true;
-                    self.defender(game, k, &mut x)
-                } else {
-                    self.player(game, k)
+    /// Event loop to watch a recorded game, given the state before each
+    /// turn (and after the last) and whose turn each was
+    pub fn replay(&mut self, states: &[Game], players: &[usize]) -> Result<()> {
+        let last = states.len() - 1;
+        let mut turn = 0;
+        let mut playing = false;
+        let mut timer = Instant::now();
+        let mut redraw = true;
+
+        loop {
+            let game = &states[turn];
+            if redraw {
+                self.display_view(game)?;
+                let next = match players.get(turn) {
+                    Some(&current) => format!("next up {}", game.whose(current)),
+                    None => "game over".to_string(),
                 };
-            } else {
-                continue;
+                let state = if playing { "playing" } else { "paused" };
+                self.backend.message(&format!(
+                    "Replay ({state}): turn {turn} of {last}, {next}, {}. [SPACE] play/pause, [LEFT]/[RIGHT] step, [TAB] switch view, [q] quit",
+                    self.view_name(game)
+                ))?;
+                redraw = false;
             }
 
-            // Check for guard elimination
-            for guard in game.guards.iter_mut() {
-                if let Some((pos, _)) = game.positions[game.player] {
-                    if let Some((guard_pos, _)) = guard {
-                        if *guard_pos == pos {
-                            *guard = None;
-                        }
-                    }
+            match self.backend.input(Duration::from_millis(100))? {
+                Some(Key::Char('q')) => return Ok(()),
+                Some(Key::Char(' ')) => {
+                    playing = !playing;
+                    timer = Instant::now();
+                    redraw = true;
                 }
+                Some(Key::Right) if turn < last => {
+                    turn += 1;
+                    playing = false;
+                    redraw = true;
+                }
+                Some(Key::Left) if turn > 0 => {
+                    // Forget what was seen in the future
+                    self.seen.clear();
+                    turn -= 1;
+                    playing = false;
+                    redraw = true;
+                }
+                Some(Key::Tab) => {
+                    self.switch_view(game);
+                    redraw = true;
+                }
+                _ => (),
             }
 
-            // Check for attacker elimination (on their turn only)
-            if game.visible(game.player) {
-                detected -= 1;
+            // Move on a turn every so often while playing
+            if playing && timer.elapsed() >= REPLAY_SPEED {
+                if turn < last {
+                    turn += 1;
+                } else {
+                    playing = false;
+                }
+                timer = Instant::now();
+                redraw = true;
             }
-            if detected == 0 {
-                game.positions[game.player] = None;
+        }
+    }
+
+    /// Event loop to get user input, for as long as the turn lasts
+    pub fn input(
+        &mut self,
+        game: &mut Game,
+        defender: bool,
+        time: Duration,
+    ) -> Result<Vec<Action>> {
+        let timer = Instant::now();
+        let mut turn = game.begin(game.player);
+        self.guard = turn.guard;
+        self.notice = None;
+
+        let mut actions = vec![];
+        while turn.actions > 0 && !turn.done {
+            if let Some(remaining) = time.checked_sub(timer.elapsed()) {
+                self.status(game, turn.actions, remaining)?;
+            } else {
+                break;
             }
 
-            self.display(game, defender)?;
-            if !defender {
-                if let Some((pos, _)) = game.positions[game.player] {
-                    if Some(pos) == game.targets[game.player] {
-                        game.positions[game.player] = None;
-                        break;
-                    }
+            let key = self
+                .backend
+                .input(Duration::from_millis(game.config.input_timeout))?;
+            if let (Some(Key::Char('r')), false) = (key, defender) {
+                self.running = !self.running;
+                continue;
+            }
+            if let Some(action) = key.and_then(|k| self.action(k, game, defender)) {
+                // Only keep actions the server will accept
+                if game.act(&mut turn, action).is_ok() {
+                    actions.push(action);
                 }
+            } else {
+                continue;
             }
+
+            self.guard = turn.guard;
+            self.display(game, defender)?;
         }
 
-        Ok(())
+        Ok(actions)
     }
 
     /// Event loop to for placing guard positions
     pub fn place_guards(&mut self, game: &mut Game) -> Result<()> {
         let mut remaining: usize = game.config.num_guards;
         self.guard = 0;
+        self.running = false;
         let mut final_choice = vec![];
 
         // Hide player positions
         let players = game.positions.clone();
         game.positions = vec![None; game.config.players];
 
+        // The server only waits as long as the first turn could take
+        let timer = Instant::now();
+        let time = game.config.turn_time + game.config.time_bank;
+
         self.display_defender(game, true)?;
         while remaining > 0 {
-            self.message(&format!("{} guards remaining to place", remaining))?;
+            let Some(left) = time.checked_sub(timer.elapsed()) else {
+                // Those left to place stay where they are
+                final_choice.extend(game.guards.iter().filter(|x| x.is_some()));
+                break;
+            };
+            self.message(&format!(
+                "{} guards remaining to place, {}s left",
+                remaining,
+                left.as_secs()
+            ))?;
 
             if let Some(k) = self
                 .backend
                 .input(Duration::from_millis(game.config.input_timeout))?
             {
-                let mut done = false;
-                let _ = self.defender(game, k, &mut done);
-                if done {
-                    final_choice.push(game.guards[self.guard]);
-                    game.guards[self.guard] = None;
-                    remaining -= 1;
-                    self.guard = game.guards.iter().position(|&x| x.is_some()).unwrap_or(0);
+                match (k, self.action(k, game, true)) {
+                    (Key::Char(' '), _) => {
+                        final_choice.push(game.guards[self.guard]);
+                        game.guards[self.guard] = None;
+                        remaining -= 1;
+                        self.guard = game.guards.iter().position(|&x| x.is_some()).unwrap_or(0);
+                    }
+                    (_, Some(Action::Cycle)) => {
+                        if game.guards.iter().any(|x| x.is_some()) {
+                            loop {
+                                self.guard = (self.guard + 1) % game.guards.len();
+                                if game.guards[self.guard].is_some() {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    (_, Some(Action::Move(dir))) => {
+                        game.move_guard(self.guard, dir);
+                    }
+                    (_, Some(Action::Rotate(clockwise))) => {
+                        game.rotate_guard(self.guard, clockwise);
+                    }
+                    _ => continue,
                 }
             } else {
                 continue;
@@ -438,8 +648,116 @@ impl<T: UIBackend> UserInterface<T> {
         Ok(())
     }
 
+    /// Display the latest state of the lobby
+    pub fn lobby(&mut self, lobby: Lobby) -> Result<()> {
+        self.lobby = Some(lobby);
+        self.display_lobby()
+    }
+
+    /// Display the lobby on terminal
+    fn display_lobby(&mut self) -> Result<()> {
+        let lobby = match &self.lobby {
+            Some(lobby) => lobby,
+            None => return Ok(()),
+        };
+        self.backend.clear()?;
+
+        let mut p = (2, 1);
+        let title = format!("Lobby: {} of {} players", lobby.players.len(), lobby.size);
+        self.backend.draw(p, &title, Colour::Red, Colour::Reset)?;
+        p.1 += 2;
+
+        for (i, player) in lobby.players.iter().enumerate() {
+            let role = match player.role {
+                Role::Any => "any role",
+                Role::Defender => "defender",
+                Role::Attacker => "attacker",
+            };
+            let line = format!(
+                "{} {:<20} {:<10} {}{}",
+                if i == lobby.you { ">" } else { " " },
+                player.profile.name,
+                role,
+                if player.ready { "ready" } else { "not ready" },
+                if i == 0 { " (host)" } else { "" },
+            );
+            let fg = match (player.ready, player.profile.colour) {
+                (false, _) => Colour::Grey,
+                (true, Some(colour)) => colour,
+                (true, None) => Colour::Green,
+            };
+            self.backend.draw(p, &line, fg, Colour::Reset)?;
+            p.1 += 1;
+        }
+
+        p.1 += 1;
+        for line in lobby.chat.iter() {
+            self.backend.draw(p, line, Colour::Grey, Colour::Reset)?;
+            p.1 += 1;
+        }
+
+        match &self.typing {
+            Some(text) => self.backend.message(&format!("Say: {text}_"))?,
+            None => self.backend.message(
+                "[d]efender [a]ttacker [n]o preference [r]eady [c]hat [s]tart (host) [q]uit",
+            )?,
+        }
+        self.backend.flush()?;
+        Ok(())
+    }
+
+    /// Get a request from the player in the lobby
+    pub fn lobby_input(&mut self) -> Result<Option<LobbyAction>> {
+        let key = match self.backend.input(Duration::from_millis(50))? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+
+        // Typing a chat message
+        if let Some(text) = self.typing.as_mut() {
+            let mut action = None;
+            match key {
+                Key::Enter => {
+                    if !text.is_empty() {
+                        action = Some(LobbyAction::Chat(text.clone()));
+                    }
+                    self.typing = None;
+                }
+                Key::Esc => self.typing = None,
+                Key::Backspace => {
+                    text.pop();
+                }
+                Key::Char(c) => text.push(c),
+                _ => (),
+            }
+            self.display_lobby()?;
+            return Ok(action);
+        }
+
+        let ready = self
+            .lobby
+            .as_ref()
+            .and_then(|l| l.players.get(l.you))
+            .is_some_and(|p| p.ready);
+        let action = match key {
+            Key::Char('d') => LobbyAction::Role(Role::Defender),
+            Key::Char('a') => LobbyAction::Role(Role::Attacker),
+            Key::Char('n') => LobbyAction::Role(Role::Any),
+            Key::Char('r') => LobbyAction::Ready(!ready),
+            Key::Char('s') => LobbyAction::Start,
+            Key::Char('c') | Key::Enter => {
+                self.typing = Some(String::new());
+                self.display_lobby()?;
+                return Ok(None);
+            }
+            Key::Char('q') => return Err("Left the lobby".into()),
+            _ => return Ok(None),
+        };
+        Ok(Some(action))
+    }
+
     /// Splash screen
-    pub fn splash(&mut self) -> Result<()> {
+    pub fn splash(&mut self, token: u64) -> Result<()> {
         const SPLASH: &str = "██   ██  █████  ███    ██ ███████  ██████
 ██   ██ ██   ██ ████   ██    ███  ██    ██
 ███████ ███████ ██ ██  ██   ███   ██    ██
@@ -456,6 +774,13 @@ impl<T: UIBackend> UserInterface<T> {
             p.1 += 1;
         }
         self.backend.draw(p, VERSION, Colour::Blue, Colour::Reset)?;
+        p.1 += 2;
+        self.backend.draw(
+            p,
+            &format!("Session token (to rejoin with --token): {token}"),
+            Colour::Grey,
+            Colour::Reset,
+        )?;
         self.backend.flush()?;
         Ok(())
     }
@@ -466,14 +791,13 @@ impl<T: UIBackend> UserInterface<T> {
     }
 
     /// Idle screen
-    pub fn idle(&mut self, begun: bool) -> Result<bool> {
+    pub fn idle<R: Rng + ?Sized>(&mut self, begun: bool, rng: &mut R) -> Result<bool> {
         // Consume accidental input
         if let Some(Key::Char('q')) = self.backend.input(Duration::from_millis(100))? {
             return Ok(true);
         }
         if begun {
             // Draw @s at random points on the screen
-            let mut rng = thread_rng();
             let size = self.backend.size();
             for _ in 0..(size.0 / 4) {
                 let x = rng.gen_range(0..size.0);
@@ -482,7 +806,14 @@ impl<T: UIBackend> UserInterface<T> {
                     .draw((x, y), "@", Colour::Magenta, Colour::Reset)?;
             }
         }
-        self.message("Waiting for other players...")?;
+        let waiting = match &self.waiting {
+            Some(name) => format!("Waiting for {name}..."),
+            None => "Waiting for other players...".to_string(),
+        };
+        match &self.notice {
+            Some(notice) => self.backend.message(&format!("{notice} {waiting}")),
+            None => self.backend.message(&waiting),
+        }?;
         Ok(false)
     }
 }
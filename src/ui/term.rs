@@ -77,10 +77,17 @@ impl UIBackend for Terminal {
                     // Map from Crossterm keys to our basic set
                     let k = match event.code {
                         KeyCode::Tab => Key::Tab,
+                        KeyCode::Enter => Key::Enter,
+                        KeyCode::Backspace => Key::Backspace,
+                        KeyCode::Esc => Key::Esc,
                         KeyCode::Left => Key::Left,
                         KeyCode::Down => Key::Down,
                         KeyCode::Up => Key::Up,
                         KeyCode::Right => Key::Right,
+                        KeyCode::Home => Key::Home,
+                        KeyCode::End => Key::End,
+                        KeyCode::PageUp => Key::PageUp,
+                        KeyCode::PageDown => Key::PageDown,
                         KeyCode::Char(c) => Key::Char(c),
                         _ => return Ok(None),
                     };